@@ -0,0 +1,111 @@
+/// Decodes standard base64, ignoring whitespace. Returns `None` if `s` is not
+/// valid base64.
+pub fn decode(s: &str) -> Option<Vec<u8>> {
+    let mut ret = Vec::with_capacity(s.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    let mut padding = 0;
+
+    for c in s.bytes().filter(|c| !(*c as char).is_whitespace()) {
+        let value = match c {
+            b'A'...b'Z' => c - b'A',
+            b'a'...b'z' => c - b'a' + 26,
+            b'0'...b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => {
+                padding += 1;
+                continue;
+            }
+            _ => return None,
+        };
+
+        // Padding only ends the data
+        if padding > 0 {
+            return None;
+        }
+
+        // Only the bits not yet output are needed
+        acc = (acc << 6 | value as u32) & 0xffff;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            ret.push((acc >> bits) as u8);
+        }
+    }
+
+    if padding > 2 {
+        return None;
+    }
+
+    Some(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ALPHABET: &'static [u8] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    fn encode(data: &[u8]) -> String {
+        let mut ret = String::new();
+
+        for chunk in data.chunks(3) {
+            let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+            let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    ret.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+                } else {
+                    ret.push('=');
+                }
+            }
+        }
+
+        ret
+    }
+
+    #[test]
+    fn decodes_test_vectors() {
+        let vectors: &[(&str, &[u8])] = &[
+            ("", b""),
+            ("Zg==", b"f"),
+            ("Zm8=", b"fo"),
+            ("Zm9v", b"foo"),
+            ("Zm9vYg==", b"foob"),
+            ("Zm9vYmE=", b"fooba"),
+            ("Zm9vYmFy", b"foobar"),
+        ];
+
+        for &(encoded, decoded) in vectors {
+            assert_eq!(decode(encoded).unwrap(), decoded, "{}", encoded);
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let data: Vec<u8> = (0..256).map(|b| b as u8).collect();
+
+        for len in 0..data.len() {
+            let encoded = encode(&data[..len]);
+            assert_eq!(decode(&encoded).unwrap(), &data[..len]);
+        }
+    }
+
+    #[test]
+    fn ignores_whitespace() {
+        assert_eq!(decode("Zm9v\r\nYmFy\n").unwrap(), b"foobar");
+        assert_eq!(decode(" Zm 8= ").unwrap(), b"fo");
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        assert!(decode("Zm9v!").is_none());
+        assert!(decode("Zm9v-_").is_none());
+        assert!(decode("Zg==Zg==").is_none());
+        assert!(decode("Zg===").is_none());
+    }
+}
@@ -0,0 +1,202 @@
+use futures::{Future, Async, Poll};
+use futures::task::{self, Task};
+
+use tokio_timer::Timer;
+
+use std::{fmt, io};
+use std::sync::{Arc, Mutex, Once};
+use std::time::{Duration, Instant};
+
+/// Source of time for a `FixtureIo`, used for scripted waits, deadlines and
+/// rate limits.
+pub trait Clock: Send {
+    /// Returns the current time
+    fn now(&self) -> Instant;
+
+    /// Returns a future that completes once `duration` has passed
+    fn sleep(&self, duration: Duration) -> Delay;
+}
+
+/// Future returned by `Clock::sleep`
+pub type Delay = Box<Future<Item = (), Error = io::Error> + Send>;
+
+/// Clock following real time, backed by a `tokio_timer::Timer`
+#[derive(Debug, Clone)]
+pub struct RealClock {
+    timer: Timer,
+}
+
+/// Clock that only moves when `advance` is called
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    inner: Arc<Mutex<MockInner>>,
+}
+
+#[derive(Debug)]
+struct MockInner {
+    start: Instant,
+    now: Instant,
+    // Tasks waiting on a `MockDelay`
+    waiters: Vec<Task>,
+}
+
+struct MockDelay {
+    inner: Arc<Mutex<MockInner>>,
+    until: Instant,
+}
+
+/// Clock running at a multiple of the speed of another clock
+#[derive(Debug, Clone)]
+pub struct ScaledClock<C> {
+    inner: C,
+    speed: f64,
+    start: Instant,
+}
+
+impl RealClock {
+    pub fn new(timer: Timer) -> RealClock {
+        RealClock { timer: timer }
+    }
+}
+
+impl Default for RealClock {
+    /// Returns a clock backed by a timer shared by all fixtures, so that
+    /// fixtures don't each spawn a timer thread
+    fn default() -> RealClock {
+        RealClock::new(shared_timer())
+    }
+}
+
+/// Returns the timer used by `RealClock::default`, creating it on first use
+fn shared_timer() -> Timer {
+    static INIT: Once = Once::new();
+    static mut TIMER: *const Mutex<Timer> = 0 as *const _;
+
+    unsafe {
+        // Written once, then only read
+        INIT.call_once(|| {
+            TIMER = Box::into_raw(Box::new(Mutex::new(Timer::default())));
+        });
+
+        (*TIMER).lock().unwrap().clone()
+    }
+}
+
+impl Clock for RealClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Delay {
+        let sleep = self.timer.sleep(duration)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+
+        Box::new(sleep)
+    }
+}
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        let now = Instant::now();
+
+        MockClock {
+            inner: Arc::new(Mutex::new(MockInner {
+                start: now,
+                now: now,
+                waiters: vec![],
+            })),
+        }
+    }
+
+    /// Moves the clock forward, completing any sleeps that are now due
+    pub fn advance(&self, duration: Duration) {
+        let waiters = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.now += duration;
+            inner.waiters.drain(..).collect::<Vec<_>>()
+        };
+
+        for task in waiters {
+            task.notify();
+        }
+    }
+
+    /// Returns the total duration the clock has been advanced by
+    pub fn elapsed(&self) -> Duration {
+        let inner = self.inner.lock().unwrap();
+        inner.now - inner.start
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.inner.lock().unwrap().now
+    }
+
+    fn sleep(&self, duration: Duration) -> Delay {
+        Box::new(MockDelay {
+            inner: self.inner.clone(),
+            until: self.now() + duration,
+        })
+    }
+}
+
+impl Future for MockDelay {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.now >= self.until {
+            return Ok(Async::Ready(()));
+        }
+
+        if !inner.waiters.iter().any(|task| task.will_notify_current()) {
+            inner.waiters.push(task::current());
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+impl fmt::Debug for MockDelay {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("MockDelay")
+            .field("until", &self.until)
+            .finish()
+    }
+}
+
+impl<C: Clock> ScaledClock<C> {
+    /// Returns a clock on which time passes `speed` times as fast as on
+    /// `inner`, so that a speed of 10 turns a one second sleep into 100ms.
+    pub fn new(inner: C, speed: f64) -> ScaledClock<C> {
+        assert!(speed > 0.0, "speed must be greater than zero");
+
+        let start = inner.now();
+
+        ScaledClock {
+            inner: inner,
+            speed: speed,
+            start: start,
+        }
+    }
+}
+
+impl<C: Clock> Clock for ScaledClock<C> {
+    fn now(&self) -> Instant {
+        let elapsed = ::as_secs_f64(self.inner.now().duration_since(self.start));
+        self.start + ::from_secs_f64(elapsed * self.speed)
+    }
+
+    fn sleep(&self, duration: Duration) -> Delay {
+        self.inner.sleep(::from_secs_f64(::as_secs_f64(duration) / self.speed))
+    }
+}
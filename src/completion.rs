@@ -0,0 +1,444 @@
+use {Mismatch, Timeline};
+
+use futures::{Future, Async, Poll};
+use futures::sync::oneshot;
+
+use std::{cmp, error, fmt};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Future returned by `FixtureIo::completion`, resolving once the fixture is
+/// dropped
+#[derive(Debug)]
+pub struct Completion {
+    rx: oneshot::Receiver<Result<FixtureReport, FixtureError>>,
+}
+
+/// Summary of a fixture's run
+#[derive(Debug, Clone)]
+pub struct FixtureReport {
+    completed: bool,
+    current: Option<String>,
+    remaining: Vec<String>,
+    failure: Option<String>,
+    mismatches: Vec<Mismatch>,
+    bytes_read: usize,
+    bytes_written: usize,
+    divergence: Option<DivergenceReport>,
+    // Index of a read action that was not fully read, and the bytes left
+    unread: Option<(usize, usize)>,
+    polled: bool,
+    elapsed: Duration,
+    timeline: Timeline,
+}
+
+/// Describes where a run first diverged from the script: a mismatched
+/// write, a missed deadline, or the fixture being dropped early
+#[derive(Debug, Clone)]
+pub struct DivergenceReport {
+    action: usize,
+    name: Option<String>,
+    phase: Option<String>,
+    reason: String,
+    expected: Vec<u8>,
+    received: Vec<u8>,
+    action_started: Duration,
+    elapsed: Duration,
+    recent: Vec<ActionSummary>,
+}
+
+/// An action that completed, as listed by `DivergenceReport::recent_actions`
+#[derive(Debug, Clone)]
+pub struct ActionSummary {
+    index: usize,
+    description: String,
+    started: Duration,
+    completed: Duration,
+}
+
+/// Tracks the recently completed actions, for divergence reports
+#[derive(Debug, Clone, Default)]
+pub struct Tracker {
+    // The current action, with `completed` unset
+    current: Option<ActionSummary>,
+    // Number of bytes written when the current action started
+    written: usize,
+    recent: VecDeque<ActionSummary>,
+}
+
+/// Error returned by `Completion` when the script did not complete, a write
+/// did not match it, or the fixture failed
+#[derive(Debug, Clone)]
+pub struct FixtureError {
+    report: FixtureReport,
+}
+
+pub type Sender = oneshot::Sender<Result<FixtureReport, FixtureError>>;
+
+pub fn channel() -> (Sender, Completion) {
+    let (tx, rx) = oneshot::channel();
+    (tx, Completion { rx: rx })
+}
+
+/// Number of completed actions listed in a divergence report
+const RECENT_ACTIONS: usize = 5;
+
+impl Tracker {
+    /// Records the start of the action at `index`. `written` is the number of
+    /// bytes written so far.
+    pub fn start(&mut self, index: usize, description: String, now: Duration, written: usize) {
+        self.current = Some(ActionSummary {
+            index: index,
+            description: description,
+            started: now,
+            completed: now,
+        });
+        self.written = written;
+    }
+
+    /// Records the completion of the current action
+    pub fn complete(&mut self, now: Duration) {
+        if let Some(mut action) = self.current.take() {
+            action.completed = now;
+
+            if self.recent.len() == RECENT_ACTIONS {
+                self.recent.pop_front();
+            }
+
+            self.recent.push_back(action);
+        }
+    }
+
+    /// The most recently completed action
+    pub fn previous(&self) -> Option<&ActionSummary> {
+        self.recent.back()
+    }
+
+    /// Describes a divergence at the action at `index`. `written` is all the
+    /// data written so far.
+    pub fn diverge(&self,
+                   index: usize,
+                   name: Option<String>,
+                   phase: Option<String>,
+                   reason: String,
+                   expected: Vec<u8>,
+                   written: &[u8],
+                   now: Duration) -> DivergenceReport {
+        let started = match self.current {
+            Some(ref action) if action.index == index => action.started,
+            _ => now,
+        };
+
+        let received = &written[cmp::min(self.written, written.len())..];
+
+        DivergenceReport {
+            action: index,
+            name: name,
+            phase: phase,
+            reason: reason,
+            expected: expected,
+            received: received.to_vec(),
+            action_started: started,
+            elapsed: now,
+            recent: self.recent.iter().cloned().collect(),
+        }
+    }
+}
+
+pub fn report(current: Option<String>,
+              remaining: Vec<String>,
+              failure: Option<String>,
+              mismatches: Vec<Mismatch>,
+              bytes_read: usize,
+              bytes_written: usize,
+              divergence: Option<DivergenceReport>,
+              unread: Option<(usize, usize)>,
+              polled: bool,
+              elapsed: Duration,
+              timeline: Timeline) -> FixtureReport {
+    FixtureReport {
+        completed: current.is_none() && remaining.is_empty(),
+        current: current,
+        remaining: remaining,
+        failure: failure,
+        mismatches: mismatches,
+        bytes_read: bytes_read,
+        bytes_written: bytes_written,
+        divergence: divergence,
+        unread: unread,
+        polled: polled,
+        elapsed: elapsed,
+        timeline: timeline,
+    }
+}
+
+impl FixtureReport {
+    /// Returns true if every action of the script completed
+    pub fn is_complete(&self) -> bool {
+        self.completed
+    }
+
+    /// Description of the action the script stopped at, if it did not
+    /// complete
+    pub fn current_action(&self) -> Option<&str> {
+        self.current.as_ref().map(|s| &s[..])
+    }
+
+    /// Descriptions of the actions that were never reached
+    pub fn remaining_actions(&self) -> &[String] {
+        &self.remaining
+    }
+
+    /// Description of the failure, such as a missed deadline, that stopped
+    /// the script
+    pub fn failure(&self) -> Option<&str> {
+        self.failure.as_ref().map(|s| &s[..])
+    }
+
+    /// Writes that did not match the script
+    pub fn mismatches(&self) -> &[Mismatch] {
+        &self.mismatches
+    }
+
+    /// Number of bytes read from the fixture
+    pub fn bytes_read(&self) -> usize {
+        self.bytes_read
+    }
+
+    /// Number of bytes written to the fixture
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written
+    }
+
+    /// Time from the fixture being created until its script completed, or
+    /// until the report was made if it did not. In virtual time, this is
+    /// the virtual time elapsed.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Number of bytes read per second of `elapsed`
+    pub fn read_throughput(&self) -> f64 {
+        throughput(self.bytes_read, self.elapsed)
+    }
+
+    /// Number of bytes written per second of `elapsed`
+    pub fn write_throughput(&self) -> f64 {
+        throughput(self.bytes_written, self.elapsed)
+    }
+
+    /// Start and end times of the actions reached, from which the duration
+    /// of each action is available with `TimelineEntry::duration`
+    pub fn timeline(&self) -> &Timeline {
+        &self.timeline
+    }
+
+    /// Describes where the run first diverged from the script, if it did
+    pub fn divergence(&self) -> Option<&DivergenceReport> {
+        self.divergence.as_ref()
+    }
+
+    /// Index of the read action the script stopped at and the number of its
+    /// bytes that were never read, if the client stopped reading partway
+    /// through it
+    pub fn unread(&self) -> Option<(usize, usize)> {
+        self.unread
+    }
+
+    /// Returns true if the fixture was read from, written to, flushed or shut
+    /// down at least once
+    pub fn was_polled(&self) -> bool {
+        self.polled
+    }
+
+    /// Panics with a summary of the run unless the script completed without
+    /// mismatched writes or failures
+    pub fn assert_clean(&self) {
+        if let Err(e) = self.clone().into_result() {
+            panic!("{}", e);
+        }
+    }
+
+    /// Returns the report if the run succeeded, or an error carrying it
+    pub fn into_result(self) -> Result<FixtureReport, FixtureError> {
+        if self.completed && self.failure.is_none() && self.mismatches.is_empty() {
+            Ok(self)
+        } else {
+            Err(FixtureError { report: self })
+        }
+    }
+}
+
+fn throughput(bytes: usize, elapsed: Duration) -> f64 {
+    let secs = ::as_secs_f64(elapsed);
+
+    if secs == 0.0 {
+        0.0
+    } else {
+        bytes as f64 / secs
+    }
+}
+
+impl DivergenceReport {
+    /// Index of the action within the script
+    pub fn action(&self) -> usize {
+        self.action
+    }
+
+    /// Name given to the action with `FixtureIo::named`
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|s| &s[..])
+    }
+
+    /// Phase containing the action, added with `FixtureIo::phase`
+    pub fn phase(&self) -> Option<&str> {
+        self.phase.as_ref().map(|s| &s[..])
+    }
+
+    /// Why the run diverged
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    /// Data the script expected to be written, if known
+    pub fn expected(&self) -> &[u8] {
+        &self.expected
+    }
+
+    /// Data written during the action
+    pub fn received(&self) -> &[u8] {
+        &self.received
+    }
+
+    /// Time since the fixture was created when the action started
+    pub fn action_started(&self) -> Duration {
+        self.action_started
+    }
+
+    /// Time since the fixture was created when the run diverged
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// The last few actions that completed before the divergence, oldest
+    /// first
+    pub fn recent_actions(&self) -> &[ActionSummary] {
+        &self.recent
+    }
+}
+
+impl ActionSummary {
+    /// Index of the action within the script
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    /// Time since the fixture was created when the action started
+    pub fn started(&self) -> Duration {
+        self.started
+    }
+
+    /// Time since the fixture was created when the action completed
+    pub fn completed(&self) -> Duration {
+        self.completed
+    }
+}
+
+impl fmt::Display for DivergenceReport {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(fmt, "diverged at action {}", self.action));
+
+        if let Some(ref name) = self.name {
+            try!(write!(fmt, " ({})", name));
+        }
+
+        if let Some(ref phase) = self.phase {
+            try!(write!(fmt, " in phase {}", phase));
+        }
+
+        try!(write!(fmt, " after {:?}: {}", self.elapsed, self.reason));
+        try!(write!(fmt, "; expected={:?}; received={:?}",
+                    String::from_utf8_lossy(&self.expected),
+                    String::from_utf8_lossy(&self.received)));
+
+        for action in &self.recent {
+            try!(write!(fmt, "\n  completed action {} at {:?}: {}",
+                        action.index, action.completed, action.description));
+        }
+
+        Ok(())
+    }
+}
+
+impl FixtureError {
+    /// Returns the report of the failed run
+    pub fn report(&self) -> &FixtureReport {
+        &self.report
+    }
+
+    pub fn into_report(self) -> FixtureReport {
+        self.report
+    }
+}
+
+impl Future for Completion {
+    type Item = FixtureReport;
+    type Error = FixtureError;
+
+    fn poll(&mut self) -> Poll<FixtureReport, FixtureError> {
+        // The fixture always reports before dropping its sender
+        match self.rx.poll().expect("fixture dropped without reporting") {
+            Async::Ready(Ok(report)) => Ok(Async::Ready(report)),
+            Async::Ready(Err(e)) => Err(e),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl fmt::Display for FixtureError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let report = &self.report;
+
+        if let Some(ref failure) = report.failure {
+            try!(write!(fmt, "fixture failed: {}", failure));
+        } else if !report.completed && !report.polled {
+            try!(write!(fmt, "fixture was never polled; was the future using it spawned?"));
+        } else if !report.completed {
+            try!(write!(fmt, "fixture dropped before the script completed"));
+        } else {
+            try!(write!(fmt, "fixture script completed with mismatched writes"));
+        }
+
+        if let Some((action, len)) = report.unread {
+            try!(write!(fmt, "; {} bytes of read action {} were never read", len, action));
+        }
+
+        if let Some(ref current) = report.current {
+            try!(write!(fmt, "; current={}", current));
+        }
+
+        if !report.remaining.is_empty() {
+            try!(write!(fmt, "; remaining={:?}", report.remaining));
+        }
+
+        if let Some(ref divergence) = report.divergence {
+            try!(write!(fmt, "\n{}", divergence));
+        }
+
+        for mismatch in &report.mismatches {
+            try!(write!(fmt, "\n{}", mismatch));
+        }
+
+        Ok(())
+    }
+}
+
+impl error::Error for FixtureError {
+    fn description(&self) -> &str {
+        "fixture did not run as scripted"
+    }
+}
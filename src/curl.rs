@@ -0,0 +1,194 @@
+use Direction;
+
+use std::io;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+/// Data sent or received by curl, with the time since the first transfer if
+/// the trace was made with `--trace-time`
+pub type Block = (Direction, Option<Duration>, Vec<u8>);
+
+/// Width of the hex column of a dump line, following the offset: 16 bytes,
+/// each followed by a space
+const HEX_WIDTH: usize = 16 * 3;
+
+/// Loads the output of `curl --trace` from `path`.
+///
+/// Headers and data sent by curl are returned as writes, and those received
+/// as reads. TLS records (`SSL data`) and informational lines are skipped, so
+/// traces of HTTPS requests replay the plaintext exchange.
+pub fn load(path: &Path) -> io::Result<Vec<Block>> {
+    let mut text = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut text));
+
+    parse(&text)
+}
+
+/// Parses sections such as
+///
+/// ```text
+/// => Send header, 16 bytes (0x10)
+/// 0000: 47 45 54 20 2f 20 48 54 54 50 2f 31 2e 31 0d 0a GET / HTTP/1.1..
+/// <= Recv header, 17 bytes (0x11)
+/// 0000: 48 54 54 50 2f 31 2e 31 20 32 30 30 20 4f 4b 0d HTTP/1.1 200 OK.
+/// 0010: 0a                                              .
+/// ```
+///
+/// optionally prefixed with the time of day.
+fn parse(text: &str) -> io::Result<Vec<Block>> {
+    let mut blocks: Vec<Block> = vec![];
+    // Direction of the current section, or `None` while skipping it
+    let mut direction = None;
+    let mut start = None;
+
+    for (i, line) in text.lines().enumerate() {
+        let (time, line) = split_time(line);
+
+        if line.starts_with("=> ") || line.starts_with("<= ") {
+            let kind = &line[3..];
+            let transfers = kind.starts_with("Send header") || kind.starts_with("Send data") ||
+                kind.starts_with("Recv header") || kind.starts_with("Recv data");
+
+            direction = if !transfers {
+                None
+            } else if line.starts_with("=>") {
+                Some(Direction::Write)
+            } else {
+                Some(Direction::Read)
+            };
+
+            if direction.is_none() {
+                continue;
+            }
+
+            let elapsed = time.map(|time| {
+                let start = *start.get_or_insert(time);
+
+                // The trace crossed midnight
+                if time < start {
+                    time + Duration::from_secs(24 * 60 * 60) - start
+                } else {
+                    time - start
+                }
+            });
+
+            blocks.push((direction.unwrap(), elapsed, vec![]));
+            continue;
+        }
+
+        if line.starts_with("== ") || direction.is_none() {
+            continue;
+        }
+
+        // A hex dump line of the current section
+        let hex = match line.find(": ") {
+            Some(pos) if line[..pos].chars().all(|c| c.is_digit(16)) => &line[pos + 2..],
+            _ => return Err(invalid(i, "expected a hex dump line")),
+        };
+
+        let hex = hex.get(..HEX_WIDTH).unwrap_or(hex);
+        let block = &mut blocks.last_mut().unwrap().2;
+
+        for byte in hex.split_whitespace() {
+            if byte.len() != 2 {
+                break;
+            }
+
+            match u8::from_str_radix(byte, 16) {
+                Ok(byte) => block.push(byte),
+                Err(_) => break,
+            }
+        }
+    }
+
+    blocks.retain(|block| !block.2.is_empty());
+
+    Ok(blocks)
+}
+
+/// Splits the time of day added by `--trace-time` from `line`
+fn split_time(line: &str) -> (Option<Duration>, &str) {
+    let mut parts = line.splitn(2, ' ');
+    let time = parts.next().unwrap();
+
+    let mut fields = time.split(|c| c == ':' || c == '.');
+    let mut values = [0u64; 4];
+
+    for value in &mut values {
+        match fields.next().and_then(|field| field.parse().ok()) {
+            Some(v) => *value = v,
+            None => return (None, line),
+        }
+    }
+
+    if fields.next().is_some() {
+        return (None, line);
+    }
+
+    let secs = values[0] * 3600 + values[1] * 60 + values[2];
+    let time = Duration::new(secs, values[3] as u32 * 1_000);
+
+    (Some(time), parts.next().unwrap_or(""))
+}
+
+fn invalid(line: usize, msg: &str) -> io::Error {
+    let msg = format!("invalid curl trace at line {}: {}", line + 1, msg);
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_trace() {
+        let text = "\
+== Info:   Trying 10.0.0.2:80...
+=> Send header, 18 bytes (0x12)
+0000: 47 45 54 20 2f 20 48 54 54 50 2f 31 2e 31 0d 0a GET / HTTP/1.1..
+0010: 0d 0a                                           ..
+<= Recv header, 17 bytes (0x11)
+0000: 48 54 54 50 2f 31 2e 31 20 32 30 30 20 4f 4b 0d HTTP/1.1 200 OK.
+0010: 0a                                              .
+<= Recv SSL data, 5 bytes (0x5)
+0000: 17 03 03 00 10                                  .....
+<= Recv data, 2 bytes (0x2)
+0000: 68 69                                           hi
+";
+
+        let blocks = parse(text).unwrap();
+
+        assert_eq!(blocks, vec![
+            (Direction::Write, None, b"GET / HTTP/1.1\r\n\r\n".to_vec()),
+            (Direction::Read, None, b"HTTP/1.1 200 OK\r\n".to_vec()),
+            (Direction::Read, None, b"hi".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn parses_trace_time() {
+        let text = "\
+23:59:59.900000 => Send data, 1 bytes (0x1)
+0000: 61                                              a
+00:00:00.150000 <= Recv data, 1 bytes (0x1)
+0000: 62                                              b
+";
+
+        let blocks = parse(text).unwrap();
+
+        assert_eq!(blocks, vec![
+            (Direction::Write, Some(Duration::from_millis(0)), b"a".to_vec()),
+            (Direction::Read, Some(Duration::from_millis(250)), b"b".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn rejects_malformed_dump_line() {
+        let text = "=> Send data, 1 bytes (0x1)\nnot hex\n";
+
+        let err = parse(text).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
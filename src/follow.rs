@@ -0,0 +1,248 @@
+use Direction;
+
+use std::io;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Data sent by one side of the stream
+pub type Block = (Direction, Vec<u8>);
+
+/// Width of the hex column of a hex dump line, following the offset: two
+/// spaces, 16 bytes and the space between the two groups of 8
+const HEX_WIDTH: usize = 2 + 16 * 3 + 1;
+
+/// Loads a Wireshark "Follow TCP Stream" export from `path`.
+///
+/// Both the "Hex Dump" and "C Arrays" formats are supported, as is the output
+/// of `tshark -z follow,tcp,hex`. The first side of the stream, whose hex
+/// dump lines are not indented, is the client: the data it sent is returned
+/// as writes and the data it received as reads.
+pub fn load(path: &Path) -> io::Result<Vec<Block>> {
+    let mut text = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut text));
+
+    if text.contains("char peer0_") {
+        parse_c_arrays(&text)
+    } else {
+        parse_hex_dump(&text)
+    }
+}
+
+/// Parses lines such as
+///
+/// ```text
+/// 00000000  47 45 54 20 2f 20 48 54  54 50 2f 31 2e 31 0d 0a  GET / HT TP/1.1..
+///     00000000  48 54 54 50 2f 31 2e 31  20 32 30 30 20 4f 4b 0d  HTTP/1.1  200 OK.
+/// ```
+///
+/// where the data of the second side is indented.
+fn parse_hex_dump(text: &str) -> io::Result<Vec<Block>> {
+    let mut blocks = vec![];
+
+    for (i, line) in text.lines().enumerate() {
+        let trimmed = line.trim_left();
+
+        // Skip the header and footer of the tshark output
+        if !is_offset(trimmed) {
+            continue;
+        }
+
+        let direction = if trimmed.len() < line.len() {
+            Direction::Read
+        } else {
+            Direction::Write
+        };
+
+        // The hex bytes are in a fixed width column, followed by the ASCII
+        // representation of the data
+        let hex = &trimmed[8..];
+        let hex = hex.get(..HEX_WIDTH).unwrap_or(hex);
+
+        let mut data = vec![];
+
+        for byte in hex.split_whitespace() {
+            match parse_byte(byte) {
+                Some(byte) => data.push(byte),
+                None => break,
+            }
+        }
+
+        if data.is_empty() {
+            return Err(invalid(i, "expected hex bytes"));
+        }
+
+        push(&mut blocks, direction, &data);
+    }
+
+    Ok(blocks)
+}
+
+/// Parses arrays such as
+///
+/// ```text
+/// char peer0_0[] = { /* Packet 4 */
+/// 0x47, 0x45, 0x54, 0x20 };
+/// char peer1_0[] = { /* Packet 6 */
+/// 0x48, 0x54, 0x54, 0x50 };
+/// ```
+fn parse_c_arrays(text: &str) -> io::Result<Vec<Block>> {
+    let mut blocks = vec![];
+    let mut direction = None;
+
+    for (i, line) in text.lines().enumerate() {
+        let mut line = line.trim();
+
+        if line.contains("char peer0_") {
+            direction = Some(Direction::Write);
+        } else if line.contains("char peer1_") {
+            direction = Some(Direction::Read);
+        } else if !line.starts_with("0x") {
+            continue;
+        }
+
+        // Skip the declaration and its comment
+        if let Some(pos) = line.find('{') {
+            line = &line[pos + 1..];
+
+            if let Some(pos) = line.find("*/") {
+                line = &line[pos + 2..];
+            }
+        }
+
+        let direction = match direction {
+            Some(direction) => direction,
+            None => return Err(invalid(i, "data before the first array")),
+        };
+
+        let mut data = vec![];
+
+        for byte in line.split(|c| c == ',' || c == '}' || c == ';') {
+            let byte = byte.trim();
+
+            if byte.is_empty() {
+                continue;
+            }
+
+            match parse_byte(byte.trim_left_matches("0x")) {
+                Some(byte) => data.push(byte),
+                None => return Err(invalid(i, "expected a hex byte")),
+            }
+        }
+
+        push(&mut blocks, direction, &data);
+    }
+
+    Ok(blocks)
+}
+
+/// Appends `data`, merging it with the last block if it was sent by the same
+/// side
+fn push(blocks: &mut Vec<Block>, direction: Direction, data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+
+    match blocks.last_mut() {
+        Some(&mut (last, ref mut prev)) if last == direction => {
+            prev.extend_from_slice(data);
+            return;
+        }
+        _ => {}
+    }
+
+    blocks.push((direction, data.to_vec()));
+}
+
+fn parse_byte(s: &str) -> Option<u8> {
+    if s.len() == 2 {
+        u8::from_str_radix(s, 16).ok()
+    } else {
+        None
+    }
+}
+
+/// Returns true if `line` starts with the offset of a hex dump line
+fn is_offset(line: &str) -> bool {
+    let mut chars = line.chars();
+
+    chars.by_ref().take(8).filter(|c| c.is_digit(16)).count() == 8 &&
+        chars.next().map(|c| c.is_whitespace()).unwrap_or(false)
+}
+
+fn invalid(line: usize, msg: &str) -> io::Error {
+    let msg = format!("invalid follow stream export at line {}: {}", line + 1, msg);
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_hex_dump() {
+        let text = "\
+===================================================================
+Follow: tcp,hex
+Filter: tcp.stream eq 0
+Node 0: 10.0.0.1:40000
+Node 1: 10.0.0.2:80
+00000000  47 45 54 20 2f 20 48 54  54 50 2f 31 2e 31 0d 0a  GET / HT TP/1.1..
+00000010  0d 0a                                             ..
+    00000000  48 54 54 50 2f 31 2e 31  20 32 30 30 20 4f 4b 0d  HTTP/1.1  200 OK.
+    00000010  0a                                                .
+===================================================================
+";
+
+        let blocks = parse_hex_dump(text).unwrap();
+
+        assert_eq!(blocks, vec![
+            (Direction::Write, b"GET / HTTP/1.1\r\n\r\n".to_vec()),
+            (Direction::Read, b"HTTP/1.1 200 OK\r\n".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn ignores_hex_digits_in_ascii_column() {
+        // The ASCII column of a short line could be read as more bytes
+        let text = "00000000  61 62                                             ab\n";
+
+        let blocks = parse_hex_dump(text).unwrap();
+        assert_eq!(blocks, vec![(Direction::Write, b"ab".to_vec())]);
+    }
+
+    #[test]
+    fn rejects_hex_dump_line_without_bytes() {
+        let err = parse_hex_dump("00000000  zz zz\n").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn parses_c_arrays() {
+        let text = "\
+char peer0_0[] = { /* Packet 4 */
+0x47, 0x45, 0x54, 0x20 };
+char peer0_1[] = { /* Packet 5 */
+0x2f };
+char peer1_0[] = { /* Packet 6 */
+0x48, 0x54,
+0x54, 0x50 };
+";
+
+        let blocks = parse_c_arrays(text).unwrap();
+
+        assert_eq!(blocks, vec![
+            (Direction::Write, b"GET /".to_vec()),
+            (Direction::Read, b"HTTP".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn rejects_malformed_c_arrays() {
+        let err = parse_c_arrays("0x47, 0x45 };\n").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = parse_c_arrays("char peer0_0[] = {\n0x47, 0xzz };\n").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
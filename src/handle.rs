@@ -0,0 +1,479 @@
+use completion::{DivergenceReport, FixtureReport};
+use timeline::Timeline;
+
+use futures::task::{self, Task};
+
+use std::{io, mem};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Cheaply cloneable handle to a `FixtureIo`, usable after the fixture has
+/// been moved into the code under test.
+#[derive(Debug, Clone)]
+pub struct FixtureHandle {
+    shared: Arc<Mutex<Shared>>,
+}
+
+/// State shared between a `FixtureIo` and its handles
+#[derive(Debug, Clone, Default)]
+pub struct Shared {
+    // Data written during `then_capture_write` actions, by name
+    pub captures: HashMap<String, Vec<u8>>,
+    pub history: WriteHistory,
+    pub bytes_read: usize,
+    pub bytes_written: usize,
+    // Total duration of the waits skipped in virtual time
+    pub virtual_elapsed: Duration,
+    // Set by `FixtureHandle::pause`
+    pub paused: bool,
+    // Set by `FixtureHandle::abort`
+    pub aborted: Option<io::ErrorKind>,
+    // Tasks blocked on the fixture, notified on resume, abort or push
+    pub blocked: Vec<Task>,
+    // Actions added through a handle, not yet appended to the script
+    pub pushed: Vec<Pushed>,
+    // Descriptions of the current action and the first of the remaining
+    // ones, and the number of actions not yet completed
+    pub current_action: Option<String>,
+    pub remaining_actions: Vec<String>,
+    pub actions_remaining: usize,
+    // Number of `read` and `write` calls made during each action
+    pub polls: Vec<usize>,
+    // The most recent `read` and `write` calls, in order
+    pub calls: VecDeque<IoRecord>,
+    // Where the run first diverged from the script
+    pub divergence: Option<DivergenceReport>,
+    pub timeline: Timeline,
+    // Set once the fixture is dropped or finished
+    pub report: Option<FixtureReport>,
+}
+
+/// Action added through `FixtureHandle::push_read` or
+/// `FixtureHandle::push_write_expect`
+#[derive(Debug, Clone)]
+pub enum Pushed {
+    Read(Vec<u8>),
+    Write(Vec<u8>),
+}
+
+/// Progress of a `FixtureIo`, passed to `FixtureIo::then_assert`
+#[derive(Debug, Clone)]
+pub struct FixtureState {
+    bytes_read: usize,
+    bytes_written: usize,
+    elapsed: Duration,
+    history: WriteHistory,
+}
+
+/// A `read` or `write` call made on a `FixtureIo`, returned by
+/// `FixtureHandle::history`
+#[derive(Debug, Clone)]
+pub struct IoRecord {
+    direction: Direction,
+    data: Vec<u8>,
+    elapsed: Duration,
+    result: Result<usize, io::ErrorKind>,
+}
+
+/// Whether an `IoRecord` is for a `read` or a `write` call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Read,
+    Write,
+}
+
+/// Data written to a `FixtureIo`, passed to `FixtureIo::then_read_with`
+#[derive(Debug, Clone, Default)]
+pub struct WriteHistory {
+    writes: Vec<Vec<u8>>,
+}
+
+impl FixtureHandle {
+    /// Returns the data captured under `name` by `FixtureIo::then_capture_write`,
+    /// or `None` if nothing has been captured under that name yet
+    pub fn captured(&self, name: &str) -> Option<Vec<u8>> {
+        self.lock().captures.get(name).cloned()
+    }
+
+    /// Returns the total duration of the scripted waits passed so far when
+    /// the fixture runs in virtual time
+    pub fn virtual_elapsed(&self) -> Duration {
+        self.lock().virtual_elapsed
+    }
+
+    /// Number of bytes read from the fixture so far
+    pub fn bytes_read(&self) -> usize {
+        self.lock().bytes_read
+    }
+
+    /// Number of bytes written to the fixture so far
+    pub fn bytes_written(&self) -> usize {
+        self.lock().bytes_written
+    }
+
+    /// Returns all bytes accepted by `write` calls so far, concatenated
+    pub fn written(&self) -> Vec<u8> {
+        self.lock().history.bytes()
+    }
+
+    /// Describes the action the script is on, or returns `None` if the
+    /// script has completed.
+    ///
+    /// Progress is updated as the fixture is used, and when the handle is
+    /// created.
+    pub fn current_action(&self) -> Option<String> {
+        self.lock().current_action.clone()
+    }
+
+    /// Number of actions not yet completed, including the current one
+    pub fn actions_remaining(&self) -> usize {
+        self.lock().actions_remaining
+    }
+
+    /// Returns the `read` and `write` calls made on the fixture so far, in
+    /// order. Only the most recent calls are kept, as set with
+    /// `FixtureIo::call_history`.
+    pub fn history(&self) -> Vec<IoRecord> {
+        self.lock().calls.iter().cloned().collect()
+    }
+
+    /// Describes where the run first diverged from the script, if it has
+    pub fn divergence(&self) -> Option<DivergenceReport> {
+        self.lock().divergence.clone()
+    }
+
+    /// Panics with a summary of the run unless the script has completed
+    /// without diverging. Once the fixture is dropped, this checks its final
+    /// report as `FixtureReport::assert_clean` does.
+    pub fn assert_done(&self) {
+        let shared = self.lock().clone();
+
+        if let Some(report) = shared.report {
+            return report.assert_clean();
+        }
+
+        let Shared {
+            current_action,
+            remaining_actions,
+            actions_remaining,
+            divergence,
+            ..
+        } = shared;
+
+        if let Some(divergence) = divergence {
+            panic!("fixture diverged from the script; {}", divergence);
+        }
+
+        if let Some(current) = current_action {
+            panic!("fixture script not done; {} actions remaining; current={}; next={:?}",
+                   actions_remaining, current, remaining_actions);
+        }
+    }
+
+    /// Returns the start and end times of the actions reached so far
+    pub fn timeline(&self) -> Timeline {
+        self.lock().timeline.clone()
+    }
+
+    /// Freezes the fixture: until `resume` is called, all reads, writes,
+    /// flushes and shutdowns return `WouldBlock` and the script does not
+    /// progress
+    pub fn pause(&self) {
+        self.lock().paused = true;
+    }
+
+    /// Resumes a fixture frozen by `pause`, waking any tasks blocked on it
+    pub fn resume(&self) {
+        let tasks = {
+            let mut shared = self.lock();
+            shared.paused = false;
+            shared.blocked.drain(..).collect::<Vec<_>>()
+        };
+
+        for task in tasks {
+            task.notify();
+        }
+    }
+
+    /// Appends a read of `data` to the script, as `FixtureIo::then_read`
+    /// would, while the fixture is in use. A reader blocked on a fixture
+    /// built with `wait_for_pushed` is woken.
+    pub fn push_read<T: Into<Vec<u8>>>(&self, data: T) {
+        self.push(Pushed::Read(data.into()));
+    }
+
+    /// Appends an expected write of `data` to the script, as
+    /// `FixtureIo::then_write` would, while the fixture is in use
+    pub fn push_write_expect<T: Into<Vec<u8>>>(&self, data: T) {
+        self.push(Pushed::Write(data.into()));
+    }
+
+    fn push(&self, action: Pushed) {
+        let tasks = {
+            let mut shared = self.lock();
+            shared.pushed.push(action);
+            shared.blocked.drain(..).collect::<Vec<_>>()
+        };
+
+        for task in tasks {
+            task.notify();
+        }
+    }
+
+    /// Makes all further reads, writes, flushes and shutdowns fail with an
+    /// error of `kind`, regardless of the remaining script, as when the
+    /// network fails. Tasks blocked on the fixture are woken.
+    pub fn abort(&self, kind: io::ErrorKind) {
+        let tasks = {
+            let mut shared = self.lock();
+            shared.aborted = Some(kind);
+            shared.blocked.drain(..).collect::<Vec<_>>()
+        };
+
+        for task in tasks {
+            task.notify();
+        }
+    }
+
+    /// Returns true if the fixture is frozen by `pause`
+    pub fn is_paused(&self) -> bool {
+        self.lock().paused
+    }
+
+    /// Returns the number of `read` and `write` calls made during each
+    /// action reached so far, indexed by the position of the action in the
+    /// script
+    pub fn poll_counts(&self) -> Vec<usize> {
+        self.lock().polls.clone()
+    }
+
+    fn lock(&self) -> MutexGuard<Shared> {
+        lock(&self.shared)
+    }
+}
+
+impl FixtureState {
+    /// Number of bytes read from the fixture so far
+    pub fn bytes_read(&self) -> usize {
+        self.bytes_read
+    }
+
+    /// Number of bytes written to the fixture so far
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written
+    }
+
+    /// Time since the fixture was created, as measured by its clock. In
+    /// virtual time, this is the total duration of the scripted waits passed
+    /// so far.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Data written to the fixture so far
+    pub fn history(&self) -> &WriteHistory {
+        &self.history
+    }
+}
+
+impl IoRecord {
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// For a read, the data returned. For a write, the data passed in, which
+    /// may be more than was accepted.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Time since the fixture was created when the call was made, as for
+    /// `FixtureState::elapsed`
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Number of bytes transferred, or the kind of the error returned
+    pub fn result(&self) -> Result<usize, io::ErrorKind> {
+        self.result
+    }
+}
+
+impl WriteHistory {
+    /// Data accepted by each `write` call, in order
+    pub fn writes(&self) -> &[Vec<u8>] {
+        &self.writes
+    }
+
+    /// Data accepted by the most recent `write` call
+    pub fn last(&self) -> Option<&[u8]> {
+        self.writes.last().map(|data| &data[..])
+    }
+
+    /// All written data, concatenated
+    pub fn bytes(&self) -> Vec<u8> {
+        self.writes.concat()
+    }
+}
+
+pub fn new(shared: &Arc<Mutex<Shared>>) -> FixtureHandle {
+    FixtureHandle { shared: shared.clone() }
+}
+
+/// Returns the progress of the fixture. `elapsed` is the real time since the
+/// fixture was created, used unless the fixture runs in virtual time.
+pub fn state(shared: &Mutex<Shared>, elapsed: Option<Duration>) -> FixtureState {
+    let shared = lock(shared);
+
+    FixtureState {
+        bytes_read: shared.bytes_read,
+        bytes_written: shared.bytes_written,
+        elapsed: elapsed.unwrap_or(shared.virtual_elapsed),
+        history: shared.history.clone(),
+    }
+}
+
+/// Returns true if the fixture is paused, in which case the current task is
+/// notified on resume
+pub fn poll_paused(shared: &Mutex<Shared>) -> bool {
+    let mut shared = lock(shared);
+
+    if shared.paused {
+        park(&mut shared);
+    }
+
+    shared.paused
+}
+
+/// Takes the actions pushed through handles since the last call
+pub fn take_pushed(shared: &Mutex<Shared>) -> Vec<Pushed> {
+    let mut shared = lock(shared);
+
+    if shared.pushed.is_empty() {
+        return vec![];
+    }
+
+    shared.pushed.drain(..).collect()
+}
+
+/// Parks the current task until an action is pushed through a handle.
+/// Returns false, without parking, if one has been pushed already.
+pub fn wait_pushed(shared: &Mutex<Shared>) -> bool {
+    let mut shared = lock(shared);
+
+    if !shared.pushed.is_empty() {
+        return false;
+    }
+
+    park(&mut shared);
+    true
+}
+
+/// Returns the error kind passed to `FixtureHandle::abort`, if it has been
+/// called
+pub fn aborted(shared: &Mutex<Shared>) -> Option<io::ErrorKind> {
+    lock(shared).aborted
+}
+
+/// Notifies the current task, which is blocked on the fixture, on resume or
+/// abort
+pub fn block(shared: &Mutex<Shared>) {
+    park(&mut lock(shared));
+}
+
+fn park(shared: &mut Shared) {
+    if !shared.blocked.iter().any(|task| task.will_notify_current()) {
+        shared.blocked.push(task::current());
+    }
+}
+
+/// Forgets the recorded activity of the fixture and any abort, for
+/// `FixtureIo::reset`. The fixture stays paused if it was.
+pub fn reset(shared: &Mutex<Shared>) {
+    restore(shared, Shared::default());
+}
+
+/// Replaces the recorded activity of the fixture with `saved`, for
+/// `FixtureIo::restore`. The fixture stays paused if it was.
+pub fn restore(shared: &Mutex<Shared>, saved: Shared) {
+    let mut shared = lock(shared);
+    let paused = shared.paused;
+    let blocked = mem::replace(&mut shared.blocked, vec![]);
+
+    *shared = saved;
+    shared.paused = paused;
+    shared.blocked = blocked;
+}
+
+/// Returns descriptions of the current action and the first of the remaining
+/// ones
+pub fn progress(handle: &FixtureHandle) -> (Option<String>, Vec<String>) {
+    let shared = handle.lock();
+    (shared.current_action.clone(), shared.remaining_actions.clone())
+}
+
+/// Counts a `read` or `write` call made during the action at `index`,
+/// returning the number of calls made during it so far
+pub fn record_poll(shared: &Mutex<Shared>, index: usize) -> usize {
+    let mut shared = lock(shared);
+
+    if shared.polls.len() <= index {
+        shared.polls.resize(index + 1, 0);
+    }
+
+    shared.polls[index] += 1;
+    shared.polls[index]
+}
+
+/// Records a `read` or `write` call and its result, keeping the `limit` most
+/// recent calls. `elapsed` is as for `state`.
+pub fn record_call(shared: &Mutex<Shared>,
+                   direction: Direction,
+                   data: &[u8],
+                   result: &io::Result<usize>,
+                   elapsed: Option<Duration>,
+                   limit: usize) {
+    if limit == 0 {
+        return;
+    }
+
+    let mut shared = lock(shared);
+
+    while shared.calls.len() >= limit {
+        shared.calls.pop_front();
+    }
+
+    let record = IoRecord {
+        direction: direction,
+        data: data.to_vec(),
+        elapsed: elapsed.unwrap_or(shared.virtual_elapsed),
+        result: match *result {
+            Ok(n) => Ok(n),
+            Err(ref e) => Err(e.kind()),
+        },
+    };
+
+    shared.calls.push_back(record);
+}
+
+/// Records data returned by a `read` call
+pub fn record_read(shared: &Mutex<Shared>, data: &[u8]) {
+    lock(shared).bytes_read += data.len();
+}
+
+/// Records data accepted by a `write` call
+pub fn record_write(shared: &Mutex<Shared>, data: &[u8]) {
+    let mut shared = lock(shared);
+    shared.bytes_written += data.len();
+    shared.history.writes.push(data.to_vec());
+}
+
+/// Locks the shared state, ignoring poisoning so that a panicking test does
+/// not hide the state from other handles
+pub fn lock(shared: &Mutex<Shared>) -> MutexGuard<Shared> {
+    match shared.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
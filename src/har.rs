@@ -0,0 +1,319 @@
+use base64;
+use matcher::Http;
+
+use serde_json::Value;
+
+use std::io;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+/// An HTTP/1 request and its response, converted from a HAR entry
+pub struct Exchange {
+    pub request: Http,
+    // Time the server took to start responding
+    pub wait: Duration,
+    pub response: Vec<u8>,
+}
+
+/// Loads the entries of the HAR file at `path`, in order. An entry whose
+/// request can't be parsed as HTTP/1 is an `InvalidData` error.
+pub fn load(path: &Path) -> io::Result<Vec<Exchange>> {
+    let mut data = vec![];
+    try!(try!(File::open(path)).read_to_end(&mut data));
+
+    let har: Value = match ::serde_json::from_slice(&data) {
+        Ok(har) => har,
+        Err(e) => return Err(invalid(&format!("invalid JSON; {}", e))),
+    };
+
+    let entries = match har["log"]["entries"].as_array() {
+        Some(entries) => entries,
+        None => return Err(invalid("missing `log.entries`")),
+    };
+
+    let mut ret = vec![];
+
+    for (i, entry) in entries.iter().enumerate() {
+        let exchange = try!(convert(entry).map_err(|msg| {
+            invalid(&format!("entry {}: {}", i, msg))
+        }));
+
+        ret.push(exchange);
+    }
+
+    Ok(ret)
+}
+
+fn convert(entry: &Value) -> Result<Exchange, String> {
+    let request = &entry["request"];
+    let response = &entry["response"];
+
+    let method = try!(string(request, "method"));
+    let url = try!(string(request, "url"));
+    let (host, target) = try!(split_url(url));
+
+    let body = match request.get("postData") {
+        Some(post) if !post.is_null() => try!(read_body(post)),
+        _ => vec![],
+    };
+
+    let mut req = format!("{} {} HTTP/1.1\r\n", method, target).into_bytes();
+    let mut has_host = false;
+
+    for (name, value) in try!(headers(request)) {
+        // The body is sent whole, with its length given below
+        if is_framing(name) {
+            continue;
+        }
+
+        has_host |= name.eq_ignore_ascii_case("host");
+        push_header(&mut req, name, value);
+    }
+
+    // HTTP/2 captures carry the host in the `:authority` pseudo-header,
+    // which is skipped
+    if !has_host {
+        push_header(&mut req, "Host", host);
+    }
+
+    if !body.is_empty() {
+        push_header(&mut req, "Content-Length", &body.len().to_string());
+    }
+
+    req.extend_from_slice(b"\r\n");
+    req.extend_from_slice(&body);
+
+    let req = try!(Http::parse(&req));
+
+    let status = match response["status"].as_u64() {
+        Some(status) => status,
+        None => return Err("missing `response.status`".into()),
+    };
+
+    let reason = response["statusText"].as_str().unwrap_or("");
+    let body = try!(read_body(&response["content"]));
+
+    let mut res = format!("HTTP/1.1 {} {}\r\n", status, reason).into_bytes();
+
+    for (name, value) in try!(headers(response)) {
+        // HAR files hold the decoded body, so the original encoding and
+        // framing no longer apply
+        if is_framing(name) || name.eq_ignore_ascii_case("content-encoding") {
+            continue;
+        }
+
+        push_header(&mut res, name, value);
+    }
+
+    push_header(&mut res, "Content-Length", &body.len().to_string());
+    res.extend_from_slice(b"\r\n");
+    res.extend_from_slice(&body);
+
+    // Timings are in milliseconds, with -1 when not applicable
+    let wait = entry["timings"]["wait"].as_f64().unwrap_or(0.0);
+    let wait = if wait > 0.0 { wait } else { 0.0 };
+
+    Ok(Exchange {
+        request: req,
+        wait: Duration::from_millis(wait as u64),
+        response: res,
+    })
+}
+
+/// Returns the headers of a request or response, skipping HTTP/2
+/// pseudo-headers
+fn headers(message: &Value) -> Result<Vec<(&str, &str)>, String> {
+    let headers = match message["headers"].as_array() {
+        Some(headers) => headers,
+        None => return Ok(vec![]),
+    };
+
+    let mut ret = vec![];
+
+    for header in headers {
+        let name = try!(string(header, "name"));
+        let value = try!(string(header, "value"));
+
+        if !name.starts_with(':') {
+            ret.push((name, value));
+        }
+    }
+
+    Ok(ret)
+}
+
+/// Returns the body held by a `postData` or `content` object
+fn read_body(content: &Value) -> Result<Vec<u8>, String> {
+    let text = match content["text"].as_str() {
+        Some(text) => text,
+        None => return Ok(vec![]),
+    };
+
+    match content["encoding"].as_str() {
+        Some("base64") => {
+            base64::decode(text).ok_or_else(|| "invalid base64 body".to_string())
+        }
+        _ => Ok(text.as_bytes().to_vec()),
+    }
+}
+
+/// Splits an absolute URL into its authority and the request target
+fn split_url(url: &str) -> Result<(&str, String), String> {
+    // Drop the fragment, which is not sent
+    let url = match url.find('#') {
+        Some(pos) => &url[..pos],
+        None => url,
+    };
+
+    let rest = match url.find("://") {
+        Some(pos) => &url[pos + 3..],
+        None => return Err(format!("URL is not absolute: {}", url)),
+    };
+
+    let (host, target) = match rest.find(|c| c == '/' || c == '?') {
+        Some(pos) => (&rest[..pos], &rest[pos..]),
+        None => (rest, "/"),
+    };
+
+    if target.starts_with('?') {
+        Ok((host, format!("/{}", target)))
+    } else {
+        Ok((host, target.to_string()))
+    }
+}
+
+fn is_framing(name: &str) -> bool {
+    name.eq_ignore_ascii_case("content-length") ||
+        name.eq_ignore_ascii_case("transfer-encoding")
+}
+
+fn push_header(message: &mut Vec<u8>, name: &str, value: &str) {
+    message.extend_from_slice(name.as_bytes());
+    message.extend_from_slice(b": ");
+    message.extend_from_slice(value.as_bytes());
+    message.extend_from_slice(b"\r\n");
+}
+
+fn string<'a>(value: &'a Value, field: &str) -> Result<&'a str, String> {
+    value[field].as_str().ok_or_else(|| format!("missing `{}`", field))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    let msg = format!("invalid HAR file: {}", msg);
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use matcher::{MatchResult, WriteMatcher};
+
+    fn entry(json: &str) -> Value {
+        ::serde_json::from_str(json).unwrap()
+    }
+
+    fn error(json: &str) -> String {
+        match convert(&entry(json)) {
+            Ok(_) => panic!("entry converted; {}", json),
+            Err(e) => e,
+        }
+    }
+
+    /// Returns true if `request` is accepted whole by the converted request
+    fn accepts(exchange: &mut Exchange, request: &[u8]) -> bool {
+        match exchange.request.matches(request) {
+            MatchResult::Matched(n) => n == request.len() && exchange.request.is_complete(),
+            MatchResult::Mismatch(..) => false,
+        }
+    }
+
+    #[test]
+    fn converts_entry() {
+        let mut exchange = convert(&entry(r#"{
+            "request": {
+                "method": "GET",
+                "url": "https://example.com/a?b=1#top",
+                "headers": [
+                    { "name": ":authority", "value": "example.com" },
+                    { "name": "Accept", "value": "*/*" }
+                ]
+            },
+            "response": {
+                "status": 200,
+                "statusText": "OK",
+                "headers": [
+                    { "name": "Content-Encoding", "value": "gzip" },
+                    { "name": "Content-Length", "value": "99" }
+                ],
+                "content": { "text": "aGk=", "encoding": "base64" }
+            },
+            "timings": { "wait": 12.5 }
+        }"#)).unwrap();
+
+        assert!(accepts(&mut exchange,
+                        b"GET /a?b=1 HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\n"));
+        assert_eq!(exchange.wait, Duration::from_millis(12));
+        assert_eq!(exchange.response, &b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi"[..]);
+    }
+
+    #[test]
+    fn sends_post_data_with_its_length() {
+        let mut exchange = convert(&entry(r#"{
+            "request": {
+                "method": "POST",
+                "url": "http://example.com",
+                "headers": [{ "name": "Transfer-Encoding", "value": "chunked" }],
+                "postData": { "text": "a=1" }
+            },
+            "response": { "status": 204 },
+            "timings": { "wait": -1 }
+        }"#)).unwrap();
+
+        assert!(accepts(&mut exchange,
+                        b"POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 3\r\n\r\na=1"));
+        assert_eq!(exchange.wait, Duration::from_millis(0));
+        assert_eq!(exchange.response, &b"HTTP/1.1 204 \r\nContent-Length: 0\r\n\r\n"[..]);
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        let e = error(r#"{
+            "request": { "method": "GET", "url": "http://example.com/",
+                         "headers": [{ "name": "Bad Header", "value": "x" }] },
+            "response": { "status": 200 }
+        }"#);
+        assert!(e.contains("invalid HTTP request"), "{}", e);
+
+        let e = error(r#"{
+            "request": { "method": "GET X", "url": "http://example.com/" },
+            "response": { "status": 200 }
+        }"#);
+        assert!(e.contains("invalid HTTP request"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_malformed_entries() {
+        let e = error(r#"{
+            "request": { "method": "GET", "url": "/relative" },
+            "response": { "status": 200 }
+        }"#);
+        assert!(e.contains("not absolute"), "{}", e);
+
+        let e = error(r#"{
+            "request": { "method": "GET", "url": "http://example.com/" },
+            "response": {}
+        }"#);
+        assert!(e.contains("response.status"), "{}", e);
+
+        let e = error(r#"{
+            "request": { "method": "GET", "url": "http://example.com/" },
+            "response": { "status": 200, "content": { "text": "!", "encoding": "base64" } }
+        }"#);
+        assert!(e.contains("base64"), "{}", e);
+
+        let e = error(r#"{ "response": { "status": 200 } }"#);
+        assert!(e.contains("method"), "{}", e);
+    }
+}
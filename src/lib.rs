@@ -4,65 +4,624 @@ extern crate tokio_io;
 extern crate tokio_timer;
 extern crate io_dump;
 
+#[cfg(feature = "regex")]
+extern crate regex;
+
+#[cfg(feature = "http")]
+extern crate httparse;
+
+#[cfg(feature = "serde")]
+extern crate serde;
+
+#[cfg(feature = "tracing")]
+extern crate tracing;
+
+#[cfg(feature = "log")]
+#[macro_use]
+extern crate log;
+
+#[cfg(any(feature = "json", feature = "http"))]
+extern crate serde_json;
+
+#[cfg(feature = "yaml")]
+extern crate serde_yaml;
+
 use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_timer::Timer;
+use io_dump::DumpRead;
 
 use futures::{Future, Async, Poll};
 use futures::task::{self, Task};
+use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
-use tokio_timer::{Timer, Sleep};
-
-use bytes::{Buf, BufMut};
+use bytes::Buf;
 
-use std::{cmp, fmt, io};
-use std::collections::VecDeque;
+use std::{cmp, error, fmt, io, mem};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::ops::Range;
 use std::path::Path;
-use std::time::Duration;
-use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+#[cfg(any(feature = "http", feature = "yaml"))]
+mod base64;
+mod clock;
+mod completion;
+mod curl;
+mod follow;
+mod handle;
+#[cfg(feature = "http")]
+mod har;
+mod matcher;
+mod observer;
+#[cfg(feature = "pcap")]
+mod pcap;
+mod rules;
+mod record;
+mod run;
+mod timeline;
+#[cfg(feature = "yaml")]
+mod yaml;
+
+pub use clock::{Clock, Delay, RealClock, MockClock, ScaledClock};
+pub use completion::{Completion, FixtureReport, FixtureError, DivergenceReport, ActionSummary};
+pub use handle::{FixtureHandle, FixtureState, WriteHistory, IoRecord, Direction};
+pub use matcher::{WriteMatcher, MatchResult};
+pub use observer::FixtureObserver;
+#[cfg(feature = "pcap")]
+pub use pcap::StreamSelector;
+pub use rules::VerifyError;
+pub use run::{run, RunError};
+pub use timeline::{Timeline, TimelineEntry};
+
+use handle::{Shared, Pushed};
+
+use rules::{Rules, Rule, Fallback};
 
 pub struct FixtureIo {
     state: Option<State>,
     actions: VecDeque<Action>,
-    timer: Timer,
+    clock: Box<Clock>,
     read_wait: Option<Task>,
-    drop_tx: mpsc::Sender<()>,
-    drop_rx: Option<mpsc::Receiver<()>>,
+    read_eof: bool,
+    write_closed: bool,
+    eof_policy: EofPolicy,
+    extra_write: ExtraWritePolicy,
+    on_mismatch: Option<MismatchHandler>,
+    spurious_interrupts: usize,
+    interrupts_remaining: usize,
+    spurious_blocks: usize,
+    blocks_remaining: usize,
+    spurious_wakeups: usize,
+    wakeups_remaining: usize,
+    max_read_chunk: Option<usize>,
+    max_write_accept: Option<usize>,
+    fragments: Option<Fragments>,
+    read_rate: Option<Throttle>,
+    write_rate: Option<Throttle>,
+    faults: Option<Faults>,
+    write_timeout: Option<Duration>,
+    deadline: Option<Deadline>,
+    // Limit on the time taken by the whole script, armed on first use
+    script_timeout: Option<Duration>,
+    script_limit: Option<Duration>,
+    script_deadline: Option<Deadline>,
+    // When the most recent read action completed
+    read_completed: Option<Instant>,
+    rules: Rules,
+    shared: Arc<Mutex<Shared>>,
+    // Index of the next action to start
+    next_action: usize,
+    // Indices of the scripted writes dropped by `then_linger_close`, not yet
+    // passed
+    skipped: BTreeSet<usize>,
+    // Copy of the script taken on first use if `replayable` is set, restored
+    // by `reset`. `None` if the script holds actions that cannot be copied.
+    original: Option<Vec<Action>>,
+    replayable: bool,
+    started: bool,
+    // Copy of the current action as it was popped, for `snapshot`. Reads and
+    // writes are captured from their state instead, and not copied.
+    current: Option<Action>,
+    // Set by `finish`, which reports in place of `drop`
+    finished: bool,
+    // Set by the first `read`, `write`, `flush` or `shutdown` call
+    polled: bool,
+    expect_polled: bool,
+    // Name of the current action
+    action_name: Option<String>,
+    // Most bytes the current write accepts per call, and whether the next
+    // call returns `WouldBlock` first
+    write_chunk: Option<(usize, bool)>,
+    // Phases grouping the actions, outermost first, and that of the current
+    // action
+    phases: Vec<Phase>,
+    action_phase: Option<String>,
+    expect_shutdown: bool,
+    shutdown_called: bool,
+    // Panic if dropped before the script completes
+    panic_on_incomplete: bool,
+    // Panic if a single action is polled more than this many times
+    max_polls: Option<usize>,
+    created: Instant,
+    // Pass scripted waits without sleeping
+    virtual_time: bool,
+    // Factor applied to scripted waits
+    time_scale: f64,
+    // Upper bound on scripted waits
+    max_wait: Option<Duration>,
+    skip_waits: bool,
+    // Reads block once the script is exhausted, until actions are pushed
+    wait_for_pushed: bool,
+    jitter: Option<Jitter>,
+    // Notified when the fixture is dropped, one per `receiver` call
+    drop_txs: Vec<mpsc::Sender<()>>,
+    checkpoint_tx: UnboundedSender<String>,
+    checkpoint_rx: Option<UnboundedReceiver<String>>,
+    // Reported by the completion future
+    mismatches: Vec<Mismatch>,
+    failure: Option<String>,
+    // One per `completion` call
+    completion_txs: Vec<completion::Sender>,
+    events_tx: Option<UnboundedSender<FixtureEvent>>,
+    tracker: completion::Tracker,
+    recorder: Option<record::Recorder>,
+    // Span of the current action
+    #[cfg(feature = "tracing")]
+    span: Option<tracing::Span>,
+    // Log fixture activity
+    #[cfg(feature = "log")]
+    log: bool,
+    // Number of bytes previewed by `Debug`
+    debug_preview: usize,
+    // Number of calls kept for `FixtureHandle::history`
+    call_history: usize,
+    observers: Vec<Box<FixtureObserver>>,
+}
+
+/// What `read` returns once the fixture has reported EOF
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EofPolicy {
+    /// Keep returning `Ok(0)`
+    Repeat,
+    /// Return a `BrokenPipe` error
+    BrokenPipe,
+}
+
+/// How `write` behaves once the script has been fully consumed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtraWritePolicy {
+    /// Panic, reporting the unexpected data
+    Panic,
+    /// Return an error of the given kind. This is the default, with
+    /// `BrokenPipe`.
+    Error(io::ErrorKind),
+    /// Accept the data and discard it
+    Discard,
+}
+
+/// What to do when written data does not match the script
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MismatchAction {
+    /// Panic, reporting both buffers. This is the default.
+    Panic,
+    /// Return an `InvalidData` error carrying a `Mismatch`. The write action
+    /// fails, and further reads and writes return `InvalidData` as well.
+    Error,
+    /// Accept the written data as if it matched
+    Accept,
+}
+
+/// How the scripted waits of a fixture are replayed, set with
+/// `FixtureIo::replay_mode`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplayMode {
+    /// Wait for the scripted durations. This is the default.
+    Realtime,
+    /// Multiply the scripted durations by the factor, as with
+    /// `FixtureIo::scale_time`
+    Scaled(f64),
+    /// Complete waits immediately, in virtual time, as with
+    /// `FixtureIo::virtual_time`
+    Instant,
+}
+
+/// Probabilities of the faults injected by `FixtureIo::with_faults`. Each
+/// value is checked independently on every `read` and `write` call that
+/// transfers data.
+#[derive(Debug, Clone, Copy)]
+pub struct FaultConfig {
+    /// Probability of returning `WouldBlock`
+    pub would_block: f64,
+    /// Probability of returning `Interrupted`
+    pub interrupted: f64,
+    /// Probability of reading fewer bytes than are available
+    pub short_read: f64,
+    /// Probability of accepting fewer bytes than were written
+    pub short_write: f64,
+}
+
+/// Event reported on the stream returned by `FixtureIo::events`
+#[derive(Debug, Clone)]
+pub enum FixtureEvent {
+    /// The script reached the action at `index`
+    ActionStarted {
+        index: usize,
+        name: Option<String>,
+        description: String,
+    },
+    /// The action at `index` completed
+    ActionCompleted {
+        index: usize,
+    },
+    /// Written data did not match the script
+    Mismatch(Mismatch),
+    /// A read reported EOF
+    Eof,
+}
+
+/// Distribution of the duration of a wait added with
+/// `FixtureIo::then_wait_sampled`
+#[derive(Debug, Clone, Copy)]
+pub enum Dist {
+    /// Normal distribution, truncated at zero
+    Normal { mean: Duration, std_dev: Duration },
+    /// Exponential distribution
+    Exponential { mean: Duration },
+    /// Pareto distribution with minimum `scale`. Smaller values of `shape`
+    /// produce a heavier tail.
+    Pareto { scale: Duration, shape: f64 },
+}
+
+/// Options for comparing written data with `FixtureIo::then_write_with`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WriteOptions {
+    /// Compare ASCII letters case insensitively
+    pub ignore_case: bool,
+    /// Treat `\r\n` and `\n` as equal
+    pub normalize_line_endings: bool,
+}
+
+/// A rule started by `FixtureIo::on_write`, registered by calling `respond`
+#[derive(Debug)]
+#[must_use = "the rule is only registered once `respond` is called"]
+pub struct WriteRule {
+    io: FixtureIo,
+    rule: Rule,
+}
+
+/// Position of a fixture in its script and the activity recorded so far,
+/// taken with `FixtureIo::snapshot`
+#[derive(Debug)]
+pub struct Snapshot {
+    // The current action, followed by the queued ones
+    actions: Vec<Action>,
+    next_action: usize,
+    skipped: BTreeSet<usize>,
+    read_eof: bool,
+    write_closed: bool,
+    shutdown_called: bool,
+    read_completed: Option<Instant>,
+    mismatches: Vec<Mismatch>,
+    failure: Option<String>,
+    tracker: completion::Tracker,
+    shared: Shared,
+}
+
+/// Describes written data that did not match the script
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+    // Offset of `expected` and `actual` within the write action
+    base: usize,
+    // Index of the first difference
+    index: usize,
+    expected: Vec<u8>,
+    actual: Vec<u8>,
+    // Index of the write action within the script
+    action: Option<usize>,
+    // Name given to the write action
+    name: Option<String>,
+    // Phase containing the write action
+    phase: Option<String>,
+    // The actions before and after the write action
+    context: Option<String>,
 }
 
+/// Number of bytes of context included in a `Mismatch`
+const MISMATCH_WINDOW: usize = 16;
+
+/// Number of bytes per row in the hexdump of a `Mismatch`
+const HEXDUMP_WIDTH: usize = 8;
+
+/// Number of characters of the neighboring actions included in failure
+/// messages
+const CONTEXT_PREVIEW: usize = 64;
+
+/// Default number of bytes previewed by the `Debug` output of a `FixtureIo`
+const DEBUG_PREVIEW: usize = 16;
+
+/// Number of remaining actions described in the progress published to
+/// handles
+const PROGRESS_PREVIEW: usize = 8;
+
+/// Number of `read` and `write` calls kept for `FixtureHandle::history` by
+/// default
+const CALL_HISTORY: usize = 1024;
+
+/// Number of bytes buffered by `then_write_regex` before the written data is
+/// handled as a mismatch
+#[cfg(feature = "regex")]
+const REGEX_MAX_LEN: usize = 8 * 1024;
+
 #[derive(Debug)]
 enum Action {
     Read(Vec<u8>),
-    Write(Vec<u8>),
+    ReadWith(ReadFn),
+    Write(Box<WriteMatcher>),
     Wait(Duration),
+    Idle(Duration, Duration),
+    Silence(Duration),
+    ReadError(io::ErrorKind),
+    WriteError(io::ErrorKind),
+    Error(io::ErrorKind),
+    Eof,
+    Reset,
+    Shutdown,
+    Flush,
+    PeerClose(usize),
+    LingerClose,
+    Named(String, Box<Action>),
+    Within(Duration, Box<Action>),
+    // Must complete within the duration of the previous read completing
+    WithinAfterRead(Duration, Box<Action>),
+    // Accepts at most the given number of bytes per `write` call
+    Chunked(usize, Box<Action>),
+    Assert(AssertFn),
+    WaitFor(Gate),
+    Checkpoint(String),
 }
 
 enum State {
     Reading(io::Cursor<Vec<u8>>),
-    Writing(io::Cursor<Vec<u8>>),
-    Waiting(Sleep),
+    Writing(Box<WriteMatcher>),
+    Waiting(Delay, Duration),
+    Silent(Delay, Duration),
+    ReadError(Option<io::ErrorKind>),
+    WriteError(Option<io::ErrorKind>),
+    Error(Option<io::ErrorKind>),
+    Eof(bool),
+    Failed(io::ErrorKind, String),
+    // Whether `ConnectionReset` has been returned
+    Reset(bool),
+    Shutdown(bool),
+    Flush(bool),
+    PeerClosed(usize),
+    WaitingFor(Delay),
+    Idle(Idle),
+}
+
+type MismatchHandler = Box<FnMut(&[u8], &[u8], usize) -> MismatchAction + Send>;
+
+/// Computes the data for a read action once it is reached
+#[derive(Clone)]
+struct ReadFn(Arc<Mutex<FnMut(&WriteHistory, &HashMap<String, Vec<u8>>) -> Vec<u8> + Send>>);
+
+/// Checks the progress of the fixture once the action is reached
+#[derive(Clone)]
+struct AssertFn(Arc<Mutex<FnMut(&FixtureState) -> bool + Send>>);
+
+/// Idle period that wakes the task on every tick
+struct Idle {
+    end: Delay,
+    tick: Delay,
+    period: Duration,
+    duration: Duration,
+}
+
+/// Formats the first bytes of `data` as hex and ASCII
+struct Preview<'a> {
+    data: &'a [u8],
+    max: usize,
+}
+
+/// Formats an action, previewing the data of reads and expected writes
+struct ActionPreview<'a> {
+    action: &'a Action,
+    max: usize,
+}
+
+/// Formats a state, previewing the data still to be read or written
+struct StatePreview<'a> {
+    state: &'a State,
+    max: usize,
+}
+
+/// External event that the script waits for
+struct Gate(Delay);
+
+/// Named group of actions, added with `FixtureIo::phase`
+#[derive(Debug)]
+struct Phase {
+    name: String,
+    // Indices of the actions within the script
+    actions: Range<usize>,
+}
+
+/// Time limit for completing the current action
+struct Deadline {
+    sleep: Delay,
+    timeout: Duration,
+}
+
+struct Faults {
+    config: FaultConfig,
+    rng: Rng,
+}
+
+/// Splits read actions into fragments, pausing between them
+struct Fragments {
+    size: usize,
+    delay: Duration,
+    // Pause before the next fragment, and whether `read` has returned
+    // `WouldBlock` during it
+    pause: Option<(Delay, bool)>,
+}
+
+/// Random perturbation of scripted waits
+struct Jitter {
+    // Maximum change, as a fraction of the wait
+    fraction: f64,
+    rng: Rng,
+}
+
+/// Small deterministic PRNG (xorshift64*)
+#[derive(Debug, Clone)]
+struct Rng {
+    state: u64,
+}
+
+/// Token bucket limiting the rate at which bytes are transferred
+struct Throttle {
+    // Bytes per second
+    rate: f64,
+    // Maximum number of tokens that may accumulate
+    burst: f64,
+    tokens: f64,
+    last: Option<Instant>,
+    sleep: Option<Delay>,
 }
 
 impl FixtureIo {
     /// Returns a new `FixtureIo` that expects and returns nothing
     pub fn empty() -> FixtureIo {
-        let (tx, rx) = mpsc::channel();
+        let (checkpoint_tx, checkpoint_rx) = futures::sync::mpsc::unbounded();
 
         FixtureIo {
             state: None,
             actions: VecDeque::new(),
-            timer: Timer::default(),
+            clock: Box::new(RealClock::default()),
             read_wait: None,
-            drop_tx: tx,
-            drop_rx: Some(rx),
+            read_eof: false,
+            write_closed: false,
+            eof_policy: EofPolicy::Repeat,
+            extra_write: ExtraWritePolicy::Error(io::ErrorKind::BrokenPipe),
+            on_mismatch: None,
+            spurious_interrupts: 0,
+            interrupts_remaining: 0,
+            spurious_blocks: 0,
+            blocks_remaining: 0,
+            spurious_wakeups: 0,
+            wakeups_remaining: 0,
+            max_read_chunk: None,
+            max_write_accept: None,
+            fragments: None,
+            read_rate: None,
+            write_rate: None,
+            faults: None,
+            write_timeout: None,
+            deadline: None,
+            script_timeout: None,
+            script_limit: None,
+            script_deadline: None,
+            read_completed: None,
+            rules: Rules::default(),
+            shared: Arc::new(Mutex::new(Shared::default())),
+            next_action: 0,
+            skipped: BTreeSet::new(),
+            original: None,
+            replayable: false,
+            started: false,
+            current: None,
+            finished: false,
+            polled: false,
+            expect_polled: false,
+            action_name: None,
+            write_chunk: None,
+            phases: vec![],
+            action_phase: None,
+            expect_shutdown: false,
+            shutdown_called: false,
+            panic_on_incomplete: false,
+            max_polls: None,
+            created: Instant::now(),
+            virtual_time: false,
+            time_scale: 1.0,
+            max_wait: None,
+            skip_waits: false,
+            wait_for_pushed: false,
+            jitter: None,
+            drop_txs: vec![],
+            checkpoint_tx: checkpoint_tx,
+            checkpoint_rx: Some(checkpoint_rx),
+            mismatches: vec![],
+            failure: None,
+            completion_txs: vec![],
+            events_tx: None,
+            tracker: completion::Tracker::default(),
+            recorder: None,
+            #[cfg(feature = "tracing")]
+            span: None,
+            #[cfg(feature = "log")]
+            log: false,
+            debug_preview: DEBUG_PREVIEW,
+            call_history: CALL_HISTORY,
+            observers: vec![],
         }
     }
 
     pub fn load<P: AsRef<Path>>(path: P) -> io::Result<FixtureIo> {
-        use io_dump::{DumpRead, Direction};
+        FixtureIo::load_with_errors(path, &[])
+    }
+
+    /// Loads a dump, injecting errors at the given points.
+    ///
+    /// Each entry is the number of dump blocks replayed before the error and
+    /// the error kind. The error is returned from whichever of `read` or
+    /// `write` is called next, so a replayed session can end the way the
+    /// original did.
+    pub fn load_with_errors<P>(path: P, errors: &[(usize, io::ErrorKind)]) -> io::Result<FixtureIo>
+        where P: AsRef<Path>,
+    {
+        let dump = try!(DumpRead::open(path));
+        Ok(FixtureIo::from_dump(dump, errors))
+    }
+
+    /// Loads a dump from `reader`, such as a dump embedded in the test binary
+    /// with `include_bytes!`
+    pub fn load_from_reader<R: io::Read>(reader: R) -> io::Result<FixtureIo> {
+        let dump = DumpRead::new(io::BufReader::new(reader));
+        Ok(FixtureIo::from_dump(dump, &[]))
+    }
+
+    /// Loads a dump held in memory
+    pub fn load_from_bytes(data: &[u8]) -> io::Result<FixtureIo> {
+        Ok(FixtureIo::from_dump(DumpRead::new(data), &[]))
+    }
+
+    fn from_dump<T>(dump: DumpRead<T>, errors: &[(usize, io::ErrorKind)]) -> FixtureIo
+        where T: io::BufRead,
+    {
+        use io_dump::Direction;
+
+        let inject = |mut fixture: FixtureIo, at: usize| {
+            for &(i, kind) in errors {
+                if i == at {
+                    fixture = fixture.then_error(kind);
+                }
+            }
+
+            fixture
+        };
 
         let mut ret = FixtureIo::empty();
         let mut last = Duration::from_millis(0);
+        let mut n = 0;
+
+        for block in dump {
+            ret = inject(ret, n);
+            n += 1;
 
-        for block in try!(DumpRead::open(path)) {
             match block.direction() {
                 Direction::Write => {
                     let data: Vec<u8> = block.data().into();
@@ -80,205 +639,3268 @@ impl FixtureIo {
             last = block.elapsed();
         }
 
-        Ok(ret)
-    }
-
-    pub fn receiver(&mut self) -> mpsc::Receiver<()> {
-        self.drop_rx.take().unwrap()
+        inject(ret, n)
     }
 
-    pub fn then_read<T: Into<Vec<u8>>>(mut self, data: T) -> Self {
-        self.actions.push_back(Action::Read(data.into()));
-        self
-    }
+    /// Loads a Wireshark "Follow TCP Stream" export, in either the "Hex Dump"
+    /// or "C Arrays" format, or the output of `tshark -z follow,tcp,hex`.
+    ///
+    /// The first side of the stream is the client: the data it sent becomes
+    /// write actions and the data it received becomes read actions. Exports
+    /// carry no timing, so there are no waits between the actions.
+    pub fn load_follow_stream<P: AsRef<Path>>(path: P) -> io::Result<FixtureIo> {
+        let mut ret = FixtureIo::empty();
 
-    pub fn then_write<T: Into<Vec<u8>>>(mut self, data: T) -> Self {
-        self.actions.push_back(Action::Write(data.into()));
-        self
-    }
+        for (direction, data) in try!(follow::load(path.as_ref())) {
+            ret = match direction {
+                Direction::Write => ret.then_write(data),
+                Direction::Read => ret.then_read(data),
+            };
+        }
 
-    pub fn then_wait(mut self, duration: Duration) -> Self {
-        self.actions.push_back(Action::Wait(duration));
-        self
+        Ok(ret)
     }
 
-    fn state(&mut self) -> Option<&mut State> {
-        // If current action is complete, clear it
-        if self.is_current_action_complete() {
-            // Clear the state
-            self.state = None;
-        }
+    /// Loads the output of `curl --trace`, so that a fixture can be created
+    /// from a curl reproduction of a bug.
+    ///
+    /// The headers and data sent by curl become write actions, and those it
+    /// received become read actions. If the trace was made with
+    /// `--trace-time`, the reads are preceded by waits matching its timing.
+    /// TLS records are skipped, so traces of HTTPS requests replay the
+    /// plaintext exchange.
+    pub fn load_curl_trace<P: AsRef<Path>>(path: P) -> io::Result<FixtureIo> {
+        let mut ret = FixtureIo::empty();
+        let mut last = None;
 
-        if self.state.is_none() {
-            // Get the next action and prepare it
-            match self.actions.pop_front() {
-                Some(Action::Read(data)) => {
-                    let data = io::Cursor::new(data);
-                    self.state = Some(State::Reading(data));
-                }
-                Some(Action::Write(data)) => {
-                    let data = io::Cursor::new(data);
-                    self.state = Some(State::Writing(data));
+        for (direction, elapsed, data) in try!(curl::load(path.as_ref())) {
+            match direction {
+                Direction::Write => {
+                    ret = ret.then_write(data);
                 }
-                Some(Action::Wait(dur)) => {
-                    let mut sleep = self.timer.sleep(dur);
-
-                    // Poll, if ready, yield
-                    if sleep.poll().unwrap().is_ready() {
-                        task::current().notify();
+                Direction::Read => {
+                    if let (Some(elapsed), Some(last)) = (elapsed, last) {
+                        if elapsed > last {
+                            ret = ret.then_wait(elapsed - last);
+                        }
                     }
 
-                    self.state = Some(State::Waiting(sleep));
+                    ret = ret.then_read(data);
                 }
-                None => {}
             }
+
+            last = elapsed.or(last);
         }
 
-        self.state.as_mut()
+        Ok(ret)
     }
 
-    fn is_current_action_complete(&mut self) -> bool {
-        match self.state {
-            Some(State::Waiting(ref mut sleep)) => {
-                sleep.poll().unwrap().is_ready()
-            }
-            Some(State::Reading(ref buf)) => {
-                !buf.has_remaining()
-            }
-            Some(State::Writing(ref mut buf)) => {
-                !buf.has_remaining()
+    /// Loads the entries of a HAR file, as saved by browsers, converting each
+    /// into an HTTP/1.1 exchange: the request is expected to be written, as
+    /// with `then_write_http`, and the response is then read after the
+    /// entry's wait time.
+    ///
+    /// HAR files hold decoded bodies, so the `Content-Encoding` and
+    /// `Transfer-Encoding` headers are dropped and `Content-Length` is set to
+    /// the length of the decoded body. HTTP/2 pseudo-headers are dropped, and
+    /// `:authority` is sent as `Host`. An entry whose request is not valid
+    /// HTTP/1 is an `InvalidData` error.
+    #[cfg(feature = "http")]
+    pub fn load_har<P: AsRef<Path>>(path: P) -> io::Result<FixtureIo> {
+        let mut ret = FixtureIo::empty();
+
+        for exchange in try!(har::load(path.as_ref())) {
+            ret = ret.then_write_matcher(exchange.request);
+
+            if exchange.wait > Duration::from_millis(0) {
+                ret = ret.then_wait(exchange.wait);
             }
-            _ => false,
+
+            ret = ret.then_read(exchange.response);
         }
+
+        Ok(ret)
     }
 
-    fn maybe_wakeup_reader(&mut self) {
-        match self.state() {
-            Some(&mut State::Reading(..)) | None => {
-                if let Some(task) = self.read_wait.take() {
-                    task.notify();
+    /// Loads a TCP connection from a pcap capture, selected by `selector`.
+    ///
+    /// The connection's stream is reassembled, handling retransmitted and
+    /// out of order segments. Data sent by the client becomes write actions,
+    /// and data sent by the server becomes read actions preceded by waits
+    /// matching the capture's timing. Only the classic pcap format is
+    /// supported, not pcapng.
+    ///
+    /// Truncated or malformed packets of other connections are skipped. An
+    /// error is returned if one belongs to the selected connection.
+    #[cfg(feature = "pcap")]
+    pub fn load_pcap<P: AsRef<Path>>(path: P, selector: StreamSelector) -> io::Result<FixtureIo> {
+        let mut ret = FixtureIo::empty();
+        let mut last = Duration::from_millis(0);
+
+        for (direction, elapsed, data) in try!(pcap::load(path.as_ref(), selector)) {
+            match direction {
+                Direction::Write => {
+                    ret = ret.then_write(data);
+                }
+                Direction::Read => {
+                    ret = ret.then_wait(elapsed - last);
+                    ret = ret.then_read(data);
                 }
             }
-            _ => {}
+
+            last = elapsed;
         }
+
+        Ok(ret)
     }
 
-    fn poll_read(&mut self) -> Async<()> {
-        let ret = match self.state() {
-            Some(ref state) if state.is_reading() => {
-                Async::Ready(())
+    /// Loads a scenario written in YAML, so that fixtures can be authored and
+    /// reviewed without writing builder code.
+    ///
+    /// The document is a list of steps, either at the top level or under a
+    /// `steps` key. Each step is a mapping with a single action, and an
+    /// optional `label` naming it as with `named`:
+    ///
+    /// * `read` and `write` take a payload: a string, or a mapping with a
+    ///   single `text`, `hex` or `base64` key.
+    /// * `wait` takes a number of milliseconds, or a string such as `250ms`,
+    ///   `1.5s` or `100us`.
+    /// * `error`, `read_error` and `write_error` take the name of an
+    ///   `io::ErrorKind`, such as `ConnectionReset` or `connection_reset`.
+    ///
+    /// The steps `eof`, `shutdown` and `flush` are given by name alone, and
+    /// correspond to `then_eof`, `then_expect_shutdown` and
+    /// `then_expect_flush`.
+    #[cfg(feature = "yaml")]
+    pub fn load_yaml<P: AsRef<Path>>(path: P) -> io::Result<FixtureIo> {
+        use yaml::Step;
+
+        let mut ret = FixtureIo::empty();
+
+        for (step, label) in try!(yaml::load(path.as_ref())) {
+            ret = match step {
+                Step::Read(data) => ret.then_read(data),
+                Step::Write(data) => ret.then_write(data),
+                Step::Wait(duration) => ret.then_wait(duration),
+                Step::Error(kind) => ret.then_error(kind),
+                Step::ReadError(kind) => ret.then_read_error(kind),
+                Step::WriteError(kind) => ret.then_write_error(kind),
+                Step::Eof => ret.then_eof(),
+                Step::Shutdown => ret.then_expect_shutdown(),
+                Step::Flush => ret.then_expect_flush(),
+            };
+
+            if let Some(label) = label {
+                ret = ret.named(label);
             }
-            Some(_) => {
-                Async::NotReady
+        }
+
+        Ok(ret)
+    }
+
+    /// Writes the actions not yet started to an io_dump file at `path`, so
+    /// that a fixture built in code can be reloaded with `load` or shared with
+    /// other tools that read dumps.
+    ///
+    /// The data read and written is saved, with each block stamped with the
+    /// total of the scripted waits before it, so that `load` replays the
+    /// waits before reads. Waits before writes are replayed before the next
+    /// read, since dumps don't distinguish them.
+    ///
+    /// Actions that only check the caller, such as checkpoints, assertions
+    /// and expected flushes or shutdowns, are skipped, as are rules. Returns
+    /// an `InvalidInput` error, without writing the file, if the script
+    /// injects errors, closes the connection before its last action, computes
+    /// a read once reached, or uses a write matcher that does not expect
+    /// fixed data.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut blocks = vec![];
+        let mut elapsed = Duration::from_millis(0);
+
+        for (i, action) in self.actions.iter().enumerate() {
+            let last = i + 1 == self.actions.len();
+
+            match try!(action.data(last)) {
+                Some((direction, data)) => {
+                    if !data.is_empty() {
+                        blocks.push((direction, elapsed, data));
+                    }
+                }
+                None => elapsed += action.delay(),
             }
-            None => {
-                Async::Ready(())
+        }
+
+        record::save(path.as_ref(), &blocks)
+    }
+
+    /// Loads a dump, replaying its waits according to `mode`
+    pub fn load_with_mode<P: AsRef<Path>>(path: P, mode: ReplayMode) -> io::Result<FixtureIo> {
+        FixtureIo::load(path).map(|fixture| fixture.replay_mode(mode))
+    }
+
+    /// Uses `clock` for scripted waits, deadlines and rate limits instead of
+    /// real time
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.created = clock.now();
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Uses `timer` for scripted waits, deadlines and rate limits, so that
+    /// fixtures can share a timer owned by the test harness
+    pub fn with_timer(self, timer: Timer) -> Self {
+        self.with_clock(RealClock::new(timer))
+    }
+
+    #[deprecated(note = "use `completion` instead")]
+    pub fn receiver(&mut self) -> mpsc::Receiver<()> {
+        let (tx, rx) = mpsc::channel();
+        self.drop_txs.push(tx);
+        rx
+    }
+
+    /// Returns a handle for inspecting the fixture once it has been moved
+    /// into the code under test
+    pub fn handle(&self) -> FixtureHandle {
+        self.publish_progress();
+        handle::new(&self.shared)
+    }
+
+    /// Returns a future that resolves once the fixture is dropped. It yields
+    /// a report if the script completed without mismatched writes or
+    /// failures, and an error carrying the report otherwise.
+    ///
+    /// May be called any number of times; every future resolves with the
+    /// same result.
+    pub fn completion(&mut self) -> Completion {
+        let (tx, rx) = completion::channel();
+        self.completion_txs.push(tx);
+        rx
+    }
+
+    /// Rewinds the fixture to the start of its script, so that it can be run
+    /// again. The recorded activity, such as the write history, captures,
+    /// mismatches and timeline, is cleared, and actions pushed through handles
+    /// are dropped. The configuration, including write rules, is kept, but
+    /// the rules' use counts are cleared.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the script contains an action that cannot be replayed: a
+    /// `then_wait_for` action, or a write matcher that does not implement
+    /// `WriteMatcher::box_clone`. Panics if the fixture was not built with
+    /// `replayable`.
+    pub fn reset(&mut self) {
+        assert!(self.replayable, "fixture cannot be reset; build it with `replayable`");
+
+        if !self.started {
+            // Not used yet, nothing to rewind
+            handle::reset(&self.shared);
+            return;
+        }
+
+        let actions = match self.original {
+            Some(ref original) => {
+                original.iter()
+                    .map(|action| action.try_clone().unwrap())
+                    .collect()
             }
+            None => panic!("fixture script cannot be reset; it waits for an external \
+                            event or uses a write matcher that cannot be cloned"),
         };
 
-        if !ret.is_ready() {
-            self.read_wait = Some(task::current());
+        self.actions = actions;
+        self.state = None;
+        self.current = None;
+        self.next_action = 0;
+        self.skipped.clear();
+        self.action_name = None;
+        self.write_chunk = None;
+        self.read_eof = false;
+        self.write_closed = false;
+        self.shutdown_called = false;
+        self.polled = false;
+        self.deadline = None;
+        self.script_timeout = self.script_limit;
+        self.script_deadline = None;
+        self.read_completed = None;
+        self.created = self.clock.now();
+        self.mismatches.clear();
+        self.failure = None;
+        self.tracker = completion::Tracker::default();
+        self.rules.reset();
+        self.reset_faults();
+
+        #[cfg(feature = "tracing")]
+        {
+            self.span = None;
         }
 
-        ret
+        handle::reset(&self.shared);
+        self.publish_progress();
     }
-}
 
-impl io::Read for FixtureIo {
-    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
-        if !self.poll_read().is_ready() {
-            return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
-        }
+    /// Captures the position of the fixture in its script, along with the
+    /// recorded activity such as the write history and mismatches, so that
+    /// `restore` can later roll the fixture back to it.
+    ///
+    /// A read or write in progress is captured partway through, so that after
+    /// restoring, only the data not yet transferred remains. Other actions in
+    /// progress are restarted.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the remaining script contains an action that cannot be
+    /// replayed, as for `reset`.
+    pub fn snapshot(&self) -> Snapshot {
+        const MSG: &'static str = "fixture cannot be snapshotted; the script waits for an \
+                                   external event or uses a write matcher that cannot be \
+                                   cloned";
 
-        let n = match self.state() {
-            Some(&mut State::Reading(ref mut buf)) => {
-                let n = cmp::min(dst.len(), buf.remaining());
-                io::Cursor::new(&mut dst[..n]).put(buf);
-                n
+        let current = match self.state {
+            Some(ref state) if self.is_current_action_done() && !state.is_terminal() => None,
+            Some(State::Reading(ref buf)) => {
+                let pos = buf.position() as usize;
+                Some(Action::Read(buf.get_ref()[pos..].to_vec()))
             }
-            None => {
-                return Ok(0);
+            Some(State::Writing(ref matcher)) => {
+                Some(Action::Write(matcher.box_clone().expect(MSG)))
             }
-            _ => {
-                unreachable!();
+            Some(_) => Some(self.current.as_ref().and_then(Action::try_clone).expect(MSG)),
+            None => None,
+        };
+
+        // Keep the name of a partially transferred read or write
+        let current = match (current, self.action_name.clone()) {
+            (Some(action @ Action::Read(..)), Some(name)) |
+            (Some(action @ Action::Write(..)), Some(name)) => {
+                Some(Action::Named(name, Box::new(action)))
             }
+            (current, _) => current,
         };
 
-        self.maybe_wakeup_reader();
+        // Keep the limit of a partially written chunked write
+        let current = match (current, self.write_chunk) {
+            (Some(action @ Action::Write(..)), Some((max, _))) => {
+                Some(Action::Chunked(max, Box::new(action)))
+            }
+            (current, _) => current,
+        };
 
-        Ok(n)
+        let next_action = self.next_action - current.is_some() as usize;
+
+        let actions = current.into_iter()
+            .chain(self.actions.iter().map(|action| action.try_clone().expect(MSG)))
+            .collect();
+
+        let mut shared = handle::lock(&self.shared).clone();
+        shared.blocked.clear();
+
+        Snapshot {
+            actions: actions,
+            next_action: next_action,
+            skipped: self.skipped.clone(),
+            read_eof: self.read_eof,
+            write_closed: self.write_closed,
+            shutdown_called: self.shutdown_called,
+            read_completed: self.read_completed,
+            mismatches: self.mismatches.clone(),
+            failure: self.failure.clone(),
+            tracker: self.tracker.clone(),
+            shared: shared,
+        }
     }
-}
 
-impl AsyncRead for FixtureIo {
-}
+    /// Rolls the fixture back to a snapshot taken with `snapshot`, so that
+    /// the rest of the script can be replayed. The same snapshot may be
+    /// restored any number of times. Whether the fixture is paused is kept.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.actions = snapshot.actions.iter()
+            .map(|action| action.try_clone().unwrap())
+            .collect();
+        self.state = None;
+        self.current = None;
+        self.next_action = snapshot.next_action;
+        self.skipped = snapshot.skipped.clone();
+        self.action_name = None;
+        self.write_chunk = None;
+        self.read_eof = snapshot.read_eof;
+        self.write_closed = snapshot.write_closed;
+        self.shutdown_called = snapshot.shutdown_called;
+        self.deadline = None;
+        self.read_completed = snapshot.read_completed;
+        self.mismatches = snapshot.mismatches.clone();
+        self.failure = snapshot.failure.clone();
+        self.tracker = snapshot.tracker.clone();
+        self.reset_faults();
 
-impl io::Write for FixtureIo {
-    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
-        let n = match self.state() {
-            Some(&mut State::Writing(ref mut buf)) => {
-                let pos = buf.position() as usize;
-                let n;
+        #[cfg(feature = "tracing")]
+        {
+            self.span = None;
+        }
 
-                {
-                    let buf = &buf.get_ref()[pos..];
-                    n = cmp::min(buf.len(), src.len());
+        handle::restore(&self.shared, snapshot.shared.clone());
+        self.publish_progress();
+    }
 
-                    assert_eq!(&src[..n], &buf[..n]);
-                }
+    /// Consumes the fixture, checking that the script completed without
+    /// mismatched writes or failures. Returns the report of the run, or an
+    /// error carrying it.
+    ///
+    /// Unlike dropping the fixture, this never panics; `expect_polled`,
+    /// `expect_shutdown` and `panic_on_incomplete` are reported as errors
+    /// instead. The completion future resolves with the same result.
+    pub fn finish(mut self) -> Result<FixtureReport, FixtureError> {
+        if self.expect_polled && !self.polled && self.failure.is_none() {
+            self.failure = Some("fixture finished without being polled".into());
+        }
 
-                // Update the position
-                buf.set_position(pos as u64 + n as u64);
-                n
-            }
-            None => {
-                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"));
-            }
-            _ => {
-                return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
-            }
-        };
+        if self.expect_shutdown && !self.shutdown_called && self.failure.is_none() {
+            self.failure = Some("fixture finished without `shutdown` being called".into());
+        }
 
-        self.maybe_wakeup_reader();
+        let report = self.conclude("finished before the script completed");
+        self.finished = true;
 
-        Ok(n)
+        report.into_result()
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+    /// Number of actions not yet completed, including the current one
+    pub fn remaining_actions(&self) -> usize {
+        let current = self.state.is_some() && !self.is_current_action_done();
+        self.actions.len() + current as usize
     }
-}
 
-impl AsyncWrite for FixtureIo {
-    fn shutdown(&mut self) -> Poll<(), io::Error> {
-        Ok(Async::Ready(()))
+    /// Describes the action the script is on, or returns `None` if the
+    /// script has completed
+    pub fn current_action(&self) -> Option<String> {
+        self.progress(0).0
     }
-}
 
-impl Drop for FixtureIo {
+    /// Logs the activity of the fixture with the `log` crate: reads, writes
+    /// and actions at debug level, and mismatched writes at warn level
+    #[cfg(feature = "log")]
+    pub fn log_activity(mut self) -> Self {
+        self.log = true;
+        self
+    }
+
+    /// Notifies `observer` of the activity of the fixture. Observers are
+    /// called in the order they were added.
+    pub fn with_observer<O: FixtureObserver + 'static>(mut self, observer: O) -> Self {
+        self.observers.push(Box::new(observer));
+        self
+    }
+
+    /// Sets how many bytes of each pending read and expected write are
+    /// previewed by the `Debug` output of the fixture. Defaults to 16.
+    pub fn debug_preview(mut self, len: usize) -> Self {
+        self.debug_preview = len;
+        self
+    }
+
+    /// Sets how many of the most recent `read` and `write` calls are kept for
+    /// `FixtureHandle::history`. Defaults to 1024. Zero disables recording,
+    /// which avoids copying the data of every call.
+    pub fn call_history(mut self, limit: usize) -> Self {
+        self.call_history = limit;
+        self
+    }
+
+    /// Records the data actually read from and written to the fixture, with
+    /// the times of the calls, to an io_dump file at `path`. Comparing it to
+    /// the script helps to find where a run diverged.
+    pub fn record_actual_to<P: AsRef<Path>>(mut self, path: P) -> io::Result<Self> {
+        self.recorder = Some(try!(record::Recorder::create(path)));
+        Ok(self)
+    }
+
+    /// Returns the start and end times of the actions reached so far. The
+    /// same timeline is available from `FixtureHandle::timeline`.
+    pub fn timeline(&self) -> Timeline {
+        handle::lock(&self.shared).timeline.clone()
+    }
+
+    /// Returns a stream of the events of the fixture: actions starting and
+    /// completing, mismatched writes and EOF. Only events after the call are
+    /// reported, and a later call replaces the stream.
+    pub fn events(&mut self) -> UnboundedReceiver<FixtureEvent> {
+        let (tx, rx) = futures::sync::mpsc::unbounded();
+        self.events_tx = Some(tx);
+        rx
+    }
+
+    /// Returns a stream of the labels of `then_checkpoint` actions, yielded
+    /// as each checkpoint is reached.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called more than once.
+    pub fn checkpoints(&mut self) -> UnboundedReceiver<String> {
+        self.checkpoint_rx.take().expect("checkpoints already taken")
+    }
+
+    /// Sets what `read` returns after the fixture has reported EOF
+    pub fn on_read_after_eof(mut self, policy: EofPolicy) -> Self {
+        self.eof_policy = policy;
+        self
+    }
+
+    /// Sets how `write` behaves once the script has been fully consumed
+    pub fn on_extra_write(mut self, policy: ExtraWritePolicy) -> Self {
+        self.extra_write = policy;
+        self
+    }
+
+    /// Written data that does not match the script results in an `InvalidData`
+    /// error carrying a `Mismatch` instead of a panic
+    pub fn error_on_mismatch(self) -> Self {
+        self.on_mismatch(|_, _, _| MismatchAction::Error)
+    }
+
+    /// Registers a function that decides what happens when written data does
+    /// not match the script.
+    ///
+    /// The function is called with the expected bytes and the written bytes
+    /// compared by the failing `write` call, and the offset of the first byte
+    /// that differs within the write action. Both slices start at the same
+    /// position within the action, which is past its start once earlier calls
+    /// have matched part of it. When the expected bytes are not known, such as
+    /// when the write is checked by a predicate, they are empty.
+    pub fn on_mismatch<F>(mut self, f: F) -> Self
+        where F: FnMut(&[u8], &[u8], usize) -> MismatchAction + Send + 'static,
+    {
+        self.on_mismatch = Some(Box::new(f));
+        self
+    }
+
+    /// Each read and write action returns `Interrupted` `n` times before any
+    /// data is transferred
+    pub fn spurious_interrupts(mut self, n: usize) -> Self {
+        self.spurious_interrupts = n;
+        self
+    }
+
+    /// Each read and write action returns `WouldBlock` `n` times before any
+    /// data is transferred. The current task is notified each time, so it
+    /// will be polled again.
+    pub fn spurious_blocks(mut self, n: usize) -> Self {
+        self.spurious_blocks = n;
+        self
+    }
+
+    /// During each action, the first `n` times a `read` or `write` call
+    /// returns `WouldBlock` because the action is not ready for it, the
+    /// current task is notified anyway. The task is then polled again
+    /// without anything having changed.
+    pub fn spurious_wakeups(mut self, n: usize) -> Self {
+        self.spurious_wakeups = n;
+        self
+    }
+
+    /// Returns at most `n` bytes from each `read` call, even when more data is
+    /// available
+    pub fn max_read_chunk(mut self, n: usize) -> Self {
+        assert!(n > 0, "chunk size must be greater than zero");
+        self.max_read_chunk = Some(n);
+        self
+    }
+
+    /// Accepts at most `n` bytes from each `write` call, even when more of the
+    /// data matches the script
+    pub fn max_write_accept(mut self, n: usize) -> Self {
+        assert!(n > 0, "limit must be greater than zero");
+        self.max_write_accept = Some(n);
+        self
+    }
+
+    /// Delivers the data of each read action in fragments of at most `size`
+    /// bytes. Between fragments, `read` returns `WouldBlock` at least once and
+    /// the task is notified after `delay`.
+    pub fn fragment_reads(mut self, size: usize, delay: Duration) -> Self {
+        assert!(size > 0, "fragment size must be greater than zero");
+
+        self.fragments = Some(Fragments {
+            size: size,
+            delay: delay,
+            pause: None,
+        });
+        self
+    }
+
+    /// Limits the rate at which data is returned from `read`, allowing up to
+    /// `burst` bytes at once. When the limit is reached, `read` returns
+    /// `WouldBlock` and the task is notified once more data may be read.
+    pub fn read_rate(mut self, bytes_per_sec: u64, burst: u64) -> Self {
+        self.read_rate = Some(Throttle::new(bytes_per_sec, burst));
+        self
+    }
+
+    /// Limits the rate at which written data is accepted. When the limit is
+    /// reached, `write` returns `WouldBlock` and the task is notified once more
+    /// data may be written.
+    pub fn write_rate(mut self, bytes_per_sec: u64) -> Self {
+        self.write_rate = Some(Throttle::new(bytes_per_sec, bytes_per_sec));
+        self
+    }
+
+    /// Randomly injects `WouldBlock`, `Interrupted`, and short reads and writes
+    /// on top of the script. The faults injected are the same for every run
+    /// with the same `seed`.
+    pub fn with_faults(mut self, seed: u64, config: FaultConfig) -> Self {
+        self.faults = Some(Faults {
+            config: config,
+            rng: Rng::new(seed),
+        });
+        self
+    }
+
+    /// Each write action must be completed within `timeout` of it becoming
+    /// the current action. Otherwise, the fixture fails and all further reads
+    /// and writes return `TimedOut`.
+    pub fn expect_write_within(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how scripted waits are replayed, replacing any earlier
+    /// `scale_time`, `virtual_time` or `without_waits` setting
+    pub fn replay_mode(self, mode: ReplayMode) -> Self {
+        let factor = match mode {
+            ReplayMode::Scaled(factor) => factor,
+            _ => 1.0,
+        };
+
+        let mut fixture = self.scale_time(factor);
+
+        fixture.virtual_time = mode == ReplayMode::Instant;
+        fixture.skip_waits = false;
+        fixture
+    }
+
+    /// Runs the script in virtual time. Scripted waits complete immediately,
+    /// in order with the other actions, and advance a virtual clock that is
+    /// available from `FixtureHandle::virtual_elapsed`.
+    pub fn virtual_time(mut self) -> Self {
+        self.virtual_time = true;
+        self
+    }
+
+    /// Multiplies the duration of scripted waits by `factor`. A factor of 0.1
+    /// replays a script ten times as fast, and a factor of 2 at half speed.
+    pub fn scale_time(mut self, factor: f64) -> Self {
+        assert!(factor >= 0.0, "factor must not be negative");
+        self.time_scale = factor;
+        self
+    }
+
+    /// Randomly lengthens or shortens each scripted wait by up to `percent`
+    /// percent. The same `seed` always produces the same waits.
+    pub fn with_jitter(mut self, seed: u64, percent: f64) -> Self {
+        assert!(percent >= 0.0, "percent must not be negative");
+
+        self.jitter = Some(Jitter {
+            fraction: percent / 100.0,
+            rng: Rng::new(seed),
+        });
+        self
+    }
+
+    /// Limits scripted waits to at most `max`, after applying `scale_time`
+    /// and `with_jitter`. Shorter waits are unchanged.
+    pub fn cap_waits(mut self, max: Duration) -> Self {
+        self.max_wait = Some(max);
+        self
+    }
+
+    /// Keeps a copy of the script when the fixture is first used, so that
+    /// `reset` can rewind it. The copy is not kept by default, as it doubles
+    /// the memory held by large scripts.
+    pub fn replayable(mut self) -> Self {
+        self.replayable = true;
+        self
+    }
+
+    /// Skips all scripted waits, including the delays between the blocks of
+    /// a loaded dump, for tests that only depend on the order of the data.
+    pub fn without_waits(mut self) -> Self {
+        self.skip_waits = true;
+        self
+    }
+
+    /// Once the script is exhausted, reads return `WouldBlock` instead of EOF
+    /// until more actions are added with `FixtureHandle::push_read` or
+    /// `push_write_expect`, which wake the reader.
+    pub fn wait_for_pushed(mut self) -> Self {
+        self.wait_for_pushed = true;
+        self
+    }
+
+    /// Panics when the fixture is dropped if `AsyncWrite::shutdown` was never
+    /// called
+    pub fn expect_shutdown(mut self) -> Self {
+        self.expect_shutdown = true;
+        self
+    }
+
+    /// Panics when the fixture is dropped without `read`, `write`, `flush` or
+    /// `shutdown` ever being called, which usually means that the future
+    /// using it was never spawned
+    pub fn expect_polled(mut self) -> Self {
+        self.expect_polled = true;
+        self
+    }
+
+    /// Panics when the fixture is dropped before all actions have completed,
+    /// listing the remaining actions
+    pub fn panic_on_incomplete(mut self) -> Self {
+        self.panic_on_incomplete = true;
+        self
+    }
+
+    /// Allows the fixture to be dropped before all actions have completed,
+    /// undoing `panic_on_incomplete`. Use this for tests that abandon the
+    /// connection on purpose.
+    pub fn allow_incomplete(mut self) -> Self {
+        self.panic_on_incomplete = false;
+        self
+    }
+
+    /// Panics if `read` or `write` is called more than `max` times during a
+    /// single action, which usually means the code under test is busy
+    /// looping or missed a wakeup. The counts are available from
+    /// `FixtureHandle::poll_counts`.
+    pub fn max_polls_per_action(mut self, max: usize) -> Self {
+        self.max_polls = Some(max);
+        self
+    }
+
+    /// The whole script must be completed within `timeout` of the fixture
+    /// first being used. Otherwise, the fixture fails and all further reads
+    /// and writes return `TimedOut`, with an error describing where the
+    /// script stalled.
+    pub fn deadline(mut self, timeout: Duration) -> Self {
+        self.script_timeout = Some(timeout);
+        self.script_limit = Some(timeout);
+        self
+    }
+
+    pub fn then_read<T: Into<Vec<u8>>>(mut self, data: T) -> Self {
+        self.actions.push_back(Action::Read(data.into()));
+        self
+    }
+
+    /// Makes `line` available to read, followed by `\r\n`
+    pub fn then_read_line<T: Into<Vec<u8>>>(self, line: T) -> Self {
+        self.then_read(crlf(line))
+    }
+
+    /// Makes data available to read, computed from the data written so far.
+    /// `f` is called when the action is reached.
+    pub fn then_read_with<F, T>(mut self, mut f: F) -> Self
+        where F: FnMut(&WriteHistory) -> T + Send + 'static,
+              T: Into<Vec<u8>>,
+    {
+        let f = move |history: &WriteHistory, _: &HashMap<String, Vec<u8>>| f(history).into();
+        let f = ReadFn(Arc::new(Mutex::new(f)));
+        self.actions.push_back(Action::ReadWith(f));
+        self
+    }
+
+    /// Makes data available to read, computed from the data captured by
+    /// earlier `then_capture_write` actions. `f` is called when the action
+    /// is reached.
+    pub fn then_read_template<F, T>(mut self, mut f: F) -> Self
+        where F: FnMut(&HashMap<String, Vec<u8>>) -> T + Send + 'static,
+              T: Into<Vec<u8>>,
+    {
+        let f = move |_: &WriteHistory, captures: &HashMap<String, Vec<u8>>| f(captures).into();
+        let f = ReadFn(Arc::new(Mutex::new(f)));
+        self.actions.push_back(Action::ReadWith(f));
+        self
+    }
+
+    pub fn then_write<T: Into<Vec<u8>>>(self, data: T) -> Self {
+        self.then_write_matcher(matcher::Exact::new(data.into()))
+    }
+
+    /// Expects `data` to be written, comparing it as configured by `options`
+    pub fn then_write_with<T: Into<Vec<u8>>>(self, data: T, options: WriteOptions) -> Self {
+        self.then_write_matcher(matcher::Exact::new(data.into()).options(options))
+    }
+
+    /// Expects `data` to be written within `timeout` of the previous read
+    /// action completing, or of the action becoming current if no read has
+    /// completed. Otherwise, the fixture fails and all further reads and
+    /// writes return `TimedOut`.
+    pub fn then_expect_write_within<T: Into<Vec<u8>>>(mut self, data: T, timeout: Duration) -> Self {
+        let matcher = Box::new(matcher::Exact::new(data.into()));
+        let action = Action::WithinAfterRead(timeout, Box::new(Action::Write(matcher)));
+        self.actions.push_back(action);
+        self
+    }
+
+    /// Accepts exactly `len` written bytes, storing them under `name`. The
+    /// bytes are available from `FixtureHandle::captured`.
+    pub fn then_capture_write<T: Into<String>>(self, len: usize, name: T) -> Self {
+        let matcher = matcher::Capture::new(len, name.into(), self.shared.clone());
+        self.then_write_matcher(matcher)
+    }
+
+    /// Expects each of `payloads` to be written once, in any order.
+    ///
+    /// Each payload must be written in full before the next one starts.
+    /// While the written data is consistent with more than one payload, the
+    /// choice between them is deferred. When one payload is a prefix of
+    /// another, the longer one is chosen if the data written next continues
+    /// it.
+    pub fn then_write_unordered<I, T>(self, payloads: I) -> Self
+        where I: IntoIterator<Item = T>,
+              T: Into<Vec<u8>>,
+    {
+        let payloads = payloads.into_iter().map(Into::into).collect();
+        self.then_write_matcher(matcher::Unordered::new(payloads))
+    }
+
+    /// Expects `line` to be written, followed by `\r\n`
+    pub fn then_expect_line<T: Into<Vec<u8>>>(self, line: T) -> Self {
+        self.then_write(crlf(line))
+    }
+
+    /// Expects written data to satisfy `matcher`
+    pub fn then_write_matcher<M: WriteMatcher + 'static>(mut self, matcher: M) -> Self {
+        self.actions.push_back(Action::Write(Box::new(matcher)));
+        self
+    }
+
+    /// Expects `data` to be written, ignoring the contents of the bytes within
+    /// `mask`. Use this for fields, such as random IDs or timestamps, that
+    /// can't be known in advance.
+    pub fn then_write_masked<T: Into<Vec<u8>>>(self, data: T, mask: &[Range<usize>]) -> Self {
+        self.then_write_matcher(matcher::Exact::masked(data.into(), mask.to_vec()))
+    }
+
+    /// Expects exactly `len` bytes to be written, equal to `expected`.
+    ///
+    /// Unlike `then_write`, the data is only compared once all `len` bytes
+    /// have been written, so a mismatch reports the whole write rather than
+    /// the chunk in which the first difference appeared.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `expected` is not `len` bytes long.
+    pub fn then_write_exact_len<T: Into<Vec<u8>>>(self, len: usize, expected: T) -> Self {
+        self.then_write_matcher(matcher::Deferred::new(len, expected.into()))
+    }
+
+    /// Expects exactly `len` bytes to be written, without checking their
+    /// contents
+    pub fn then_write_any(self, len: usize) -> Self {
+        self.then_write_matcher(matcher::Any::new(len))
+    }
+
+    /// Expects `len` bytes to be written. Once all of them have been received,
+    /// they are passed to `f`, and a return value of `false` is handled as a
+    /// mismatch.
+    pub fn then_write_matching<F>(self, len: usize, f: F) -> Self
+        where F: FnMut(&[u8]) -> bool + Send + 'static,
+    {
+        self.then_write_matcher(matcher::Predicate::new(len, f))
+    }
+
+    /// Expects written data to match the regular expression `pattern`, which
+    /// is anchored at the start of the data.
+    ///
+    /// Written data is accepted up to the end of the match. The action
+    /// completes as soon as the data written so far matches, so `pattern`
+    /// should include a terminator, such as `\r\n`, when the length of the
+    /// data varies. If 8 KiB are written without a match, the data is handled
+    /// as a mismatch; use `then_write_regex_max_len` to change the limit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pattern` is not a valid regular expression, or if it
+    /// matches empty data.
+    #[cfg(feature = "regex")]
+    pub fn then_write_regex(self, pattern: &str) -> Self {
+        self.then_write_regex_max_len(pattern, REGEX_MAX_LEN)
+    }
+
+    /// Expects written data to match the regular expression `pattern`, as
+    /// with `then_write_regex`, handling the data as a mismatch once `max_len`
+    /// bytes have been written without a match.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pattern` is not a valid regular expression, if it matches
+    /// empty data, or if `max_len` is zero.
+    #[cfg(feature = "regex")]
+    pub fn then_write_regex_max_len(self, pattern: &str, max_len: usize) -> Self {
+        assert!(max_len > 0, "max_len must be greater than zero");
+
+        let re = regex::bytes::Regex::new(&format!("^(?:{})", pattern))
+            .unwrap_or_else(|e| panic!("invalid regex `{}`; {}", pattern, e));
+
+        // An empty match would complete the action without accepting any data
+        assert!(!re.is_match(b""), "regex `{}` matches empty data", pattern);
+
+        self.then_write_matcher(matcher::Regex::new(re, max_len))
+    }
+
+    /// Expects an HTTP/1 request equivalent to `request` to be written.
+    ///
+    /// The method, path, headers and body are compared, but the order of the
+    /// headers is not, and header names are compared case insensitively. The
+    /// length of the body is taken from the `Content-Length` header.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `request` is not a complete HTTP/1 request.
+    #[cfg(feature = "http")]
+    pub fn then_write_http<T: AsRef<[u8]>>(self, request: T) -> Self {
+        self.then_write_matcher(matcher::Http::new(request.as_ref()))
+    }
+
+    /// Expects a JSON value equivalent to `expected` to be written.
+    ///
+    /// Values are compared structurally, ignoring whitespace and the order of
+    /// object keys. Written data is accepted up to the end of the value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `expected` is not valid JSON.
+    #[cfg(feature = "json")]
+    pub fn then_write_json(self, expected: &str) -> Self {
+        self.then_write_matcher(matcher::Json::new(expected, None))
+    }
+
+    /// Expects data ending with `delimiter`, followed by a JSON value
+    /// equivalent to `expected`.
+    ///
+    /// The data up to and including the delimiter is not checked, which
+    /// allows matching the body of a message whose headers vary, for example
+    /// with `"\r\n\r\n"` as the delimiter.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `expected` is not valid JSON.
+    #[cfg(feature = "json")]
+    pub fn then_write_json_after<T: Into<Vec<u8>>>(self, delimiter: T, expected: &str) -> Self {
+        let delimiter = delimiter.into();
+        assert!(!delimiter.is_empty(), "delimiter must not be empty");
+
+        self.then_write_matcher(matcher::Json::new(expected, Some(delimiter)))
+    }
+
+    /// Expects `data` to be written as a single action, accepting at most
+    /// `max_accept` bytes per `write` call. Between calls, `write` returns
+    /// `WouldBlock` once.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_accept` is zero.
+    pub fn then_write_chunked<T: Into<Vec<u8>>>(mut self, data: T, max_accept: usize) -> Self {
+        assert!(max_accept > 0, "max_accept must be greater than zero");
+
+        let matcher = Box::new(matcher::Exact::new(data.into()));
+        let action = Action::Chunked(max_accept, Box::new(Action::Write(matcher)));
+
+        self.actions.push_back(action);
+        self
+    }
+
+    pub fn then_wait(mut self, duration: Duration) -> Self {
+        self.actions.push_back(Action::Wait(duration));
+        self
+    }
+
+    /// Sends `label` on the stream returned by `checkpoints` once the script
+    /// reaches this point, letting a test interleave its own checks with the
+    /// progress of the fixture.
+    pub fn then_checkpoint<T: Into<String>>(mut self, label: T) -> Self {
+        self.actions.push_back(Action::Checkpoint(label.into()));
+        self
+    }
+
+    /// Waits until `signal` completes, for example a `futures::sync::oneshot`
+    /// receiver. The script continues whether `signal` resolves to a value or
+    /// an error, so dropping a oneshot sender also releases the script.
+    pub fn then_wait_for<F>(mut self, signal: F) -> Self
+        where F: Future + Send + 'static,
+    {
+        let signal = signal.then(|_| Ok(()));
+        self.actions.push_back(Action::WaitFor(Gate(Box::new(signal))));
+        self
+    }
+
+    /// Keeps the connection idle for `duration`: reads and writes return
+    /// `WouldBlock` as for `then_wait`, but the current task is woken every
+    /// `tick` without anything becoming ready, as when timers fire during an
+    /// idle connection.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tick` is zero.
+    pub fn then_idle(mut self, duration: Duration, tick: Duration) -> Self {
+        assert!(tick > Duration::from_secs(0), "tick must be greater than zero");
+        self.actions.push_back(Action::Idle(duration, tick));
+        self
+    }
+
+    /// Waits for a duration sampled from `dist`. The sample is determined by
+    /// `seed` and the position of the action in the script.
+    pub fn then_wait_sampled(self, dist: Dist, seed: u64) -> Self {
+        let mut rng = Rng::new(seed.wrapping_add(self.actions.len() as u64));
+        let duration = dist.sample(&mut rng);
+        self.then_wait(duration)
+    }
+
+    /// Expects nothing to be written for `duration`. Data written during that
+    /// time is handled as a mismatch, as configured with `on_mismatch`.
+    pub fn then_expect_silence(mut self, duration: Duration) -> Self {
+        self.actions.push_back(Action::Silence(duration));
+        self
+    }
+
+    /// The next `read` returns an error of the given kind
+    pub fn then_read_error(mut self, kind: io::ErrorKind) -> Self {
+        self.actions.push_back(Action::ReadError(kind));
+        self
+    }
+
+    /// The next `read` or `write`, whichever comes first, returns an error of
+    /// the given kind
+    pub fn then_error(mut self, kind: io::ErrorKind) -> Self {
+        self.actions.push_back(Action::Error(kind));
+        self
+    }
+
+    /// Reads the first `n` bytes of `data`, then returns an error of the given
+    /// kind
+    pub fn then_read_partial_then_error<T>(self, data: T, n: usize, kind: io::ErrorKind) -> Self
+        where T: Into<Vec<u8>>,
+    {
+        let mut data = data.into();
+        data.truncate(n);
+
+        self.then_read(data).then_read_error(kind)
+    }
+
+    /// The next `read` returns EOF. Any scripted reads after this point are
+    /// never delivered.
+    pub fn then_eof(mut self) -> Self {
+        self.actions.push_back(Action::Eof);
+        self
+    }
+
+    /// Waits for the caller to invoke `AsyncWrite::shutdown`, then returns EOF
+    /// from `read`, modeling an orderly close of the connection.
+    pub fn then_graceful_close(self) -> Self {
+        self.then_expect_shutdown().then_eof()
+    }
+
+    /// Waits for the caller to invoke `AsyncWrite::shutdown`. Until it does,
+    /// reads and writes return `WouldBlock`.
+    pub fn then_expect_shutdown(mut self) -> Self {
+        self.actions.push_back(Action::Shutdown);
+        self
+    }
+
+    /// Waits for the caller to invoke `flush`. Until it does, reads and
+    /// writes return `WouldBlock`.
+    pub fn then_expect_flush(mut self) -> Self {
+        self.actions.push_back(Action::Flush);
+        self
+    }
+
+    /// All further reads and writes return `ConnectionReset`. Any actions
+    /// after this point are never reached.
+    pub fn then_reset(mut self) -> Self {
+        self.actions.push_back(Action::Reset);
+        self
+    }
+
+    /// The peer closes the connection. All further reads return EOF, and
+    /// writes accept `accept` more bytes before returning `ConnectionReset`.
+    /// Any actions after this point are never reached.
+    pub fn then_peer_close(mut self, accept: usize) -> Self {
+        self.actions.push_back(Action::PeerClose(accept));
+        self
+    }
+
+    /// The peer closes the connection, but scripted reads after this point are
+    /// still delivered before EOF is reported. All further writes return
+    /// `BrokenPipe`, and scripted writes after this point are dropped.
+    pub fn then_linger_close(mut self) -> Self {
+        self.actions.push_back(Action::LingerClose);
+        self
+    }
+
+    /// The next `write` returns an error of the given kind
+    pub fn then_write_error(mut self, kind: io::ErrorKind) -> Self {
+        self.actions.push_back(Action::WriteError(kind));
+        self
+    }
+
+    /// Queues `response` for reading when written data does not match any
+    /// rule. All data written since the last matched request is treated as
+    /// a single unmatched request.
+    pub fn otherwise_respond<T: Into<Vec<u8>>>(mut self, response: T) -> Self {
+        self.rules.set_fallback(Fallback::Respond(response.into()));
+        self
+    }
+
+    /// Returns an error of the given kind from `write` when written data
+    /// does not match any rule. The unmatched data is discarded.
+    pub fn otherwise_error(mut self, kind: io::ErrorKind) -> Self {
+        self.rules.set_fallback(Fallback::Error(kind));
+        self
+    }
+
+    /// Calls `f` with the progress of the fixture once the preceding actions
+    /// have completed, panicking if it returns `false`
+    pub fn then_assert<F>(mut self, f: F) -> Self
+        where F: FnMut(&FixtureState) -> bool + Send + 'static,
+    {
+        self.actions.push_back(Action::Assert(AssertFn(Arc::new(Mutex::new(f)))));
+        self
+    }
+
+    /// Names the action added most recently, so that failures of the action
+    /// can be identified.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no actions have been added.
+    pub fn named<T: Into<String>>(mut self, name: T) -> Self {
+        let action = match self.actions.pop_back() {
+            Some(Action::Named(_, action)) => action,
+            Some(action) => Box::new(action),
+            None => panic!("no action to name"),
+        };
+
+        self.actions.push_back(Action::Named(name.into(), action));
+        self
+    }
+
+    /// Groups the actions added by `f` into a phase named `name`. The phase of
+    /// an action is included in errors, reports and the timeline. Phases may
+    /// be nested, in which case their names are joined with `/`.
+    pub fn phase<T, F>(mut self, name: T, f: F) -> Self
+        where T: Into<String>,
+              F: FnOnce(FixtureIo) -> FixtureIo,
+    {
+        let start = self.next_action + self.actions.len();
+        let index = self.phases.len();
+
+        self.phases.push(Phase {
+            name: name.into(),
+            actions: start..start,
+        });
+
+        let mut io = f(self);
+        io.phases[index].actions.end = io.next_action + io.actions.len();
+        io
+    }
+
+    /// Requires the action added most recently to complete within `timeout`
+    /// of it becoming the current action. Otherwise, the fixture fails and
+    /// all further reads and writes return `TimedOut`, with an error naming
+    /// the action.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no actions have been added.
+    pub fn within(mut self, timeout: Duration) -> Self {
+        let action = match self.actions.pop_back() {
+            Some(Action::Within(_, action)) => action,
+            Some(action) => Box::new(action),
+            None => panic!("no action to limit"),
+        };
+
+        self.actions.push_back(Action::Within(timeout, action));
+        self
+    }
+
+    /// Starts a rule that responds when `request` is written.
+    ///
+    /// Rules apply once the script has completed. Each time the written data
+    /// starts with the request of a rule, the request is consumed and the
+    /// response is queued for reading. When more than one rule matches, the
+    /// one registered first is used. While no response is queued, `read`
+    /// returns `WouldBlock`.
+    ///
+    /// Writing data that cannot match any rule panics, unless a fallback is
+    /// set with `otherwise_respond` or `otherwise_error`.
+    pub fn on_write<T: Into<Vec<u8>>>(self, request: T) -> WriteRule {
+        let request = request.into();
+        assert!(!request.is_empty(), "request must not be empty");
+
+        WriteRule {
+            io: self,
+            rule: Rule::new(request),
+        }
+    }
+
+    /// Checks that each rule registered with `on_write` was used the number
+    /// of times set with `WriteRule::times` or `WriteRule::at_least`
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        self.rules.verify()
+    }
+
+    fn state(&mut self) -> Option<&mut State> {
+        let mut advanced = false;
+
+        if !self.started {
+            self.started = true;
+
+            // Keep a copy of the script for `reset`
+            if self.replayable {
+                self.original = self.actions.iter().map(Action::try_clone).collect();
+            }
+        }
+
+        // Append actions pushed through handles
+        for action in handle::take_pushed(&self.shared) {
+            let action = match action {
+                Pushed::Read(data) => Action::Read(data),
+                Pushed::Write(data) => Action::Write(Box::new(matcher::Exact::new(data))),
+            };
+
+            self.actions.push_back(action);
+            advanced = true;
+        }
+
+        // If current action is complete, clear it
+
+        if self.is_current_action_complete() {
+            // Clear the state
+            self.state = None;
+            self.deadline = None;
+            advanced = true;
+
+            self.complete_action();
+        } else if self.is_deadline_expired() {
+            self.fail_deadline();
+            advanced = true;
+        }
+
+        // Set once a deadline is created for a new action
+        let mut new_deadline = false;
+
+        while self.state.is_none() {
+            // Get the next action and prepare it
+            let mut action = self.actions.pop_front();
+            self.current = match action {
+                Some(ref action) if !action.is_transfer() => action.try_clone(),
+                _ => None,
+            };
+
+            if action.is_some() {
+                self.next_action = self.next_index() + 1;
+                self.skipped = self.skipped.split_off(&self.next_action);
+                self.wakeups_remaining = self.spurious_wakeups;
+                advanced = true;
+            }
+
+            self.action_name = None;
+            self.action_phase = match action {
+                Some(_) => self.phase_of(self.next_action - 1),
+                None => None,
+            };
+
+            let mut timeout = None;
+            let mut after_read = false;
+            let mut chunk = None;
+
+            // Unwrap the action, letting outer annotations take precedence
+            loop {
+                match action {
+                    Some(Action::Named(name, inner)) => {
+                        self.action_name = self.action_name.take().or(Some(name));
+                        action = Some(*inner);
+                    }
+                    Some(Action::Within(dur, inner)) => {
+                        timeout = timeout.or(Some(dur));
+                        action = Some(*inner);
+                    }
+                    Some(Action::WithinAfterRead(dur, inner)) => {
+                        if timeout.is_none() {
+                            timeout = Some(dur);
+                            after_read = true;
+                        }
+                        action = Some(*inner);
+                    }
+                    Some(Action::Chunked(max, inner)) => {
+                        chunk = chunk.or(Some(max));
+                        action = Some(*inner);
+                    }
+                    _ => break,
+                }
+            }
+
+            self.write_chunk = chunk.map(|max| (max, false));
+
+            let started = action.is_some();
+
+            if let Some(ref action) = action {
+                let description = ActionPreview { action: action, max: self.debug_preview };
+                self.start_action(format!("{:?}", description));
+            }
+
+            match action {
+                Some(Action::Read(data)) => {
+                    let data = io::Cursor::new(data);
+                    self.state = Some(State::Reading(data));
+                    self.reset_faults();
+                }
+                Some(Action::ReadWith(f)) => {
+                    // Don't hold the lock while calling `f`, which may use a
+                    // handle. Only the parts passed to `f` are copied.
+                    let (history, captures) = {
+                        let shared = handle::lock(&self.shared);
+                        (shared.history.clone(), shared.captures.clone())
+                    };
+
+                    let data = (&mut *f.0.lock().unwrap())(&history, &captures);
+                    let data = io::Cursor::new(data);
+                    self.state = Some(State::Reading(data));
+                    self.reset_faults();
+                }
+                Some(Action::Write(matcher)) => {
+                    self.state = Some(State::Writing(matcher));
+                    self.reset_faults();
+                    self.deadline = self.write_timeout.map(|t| Deadline::new(&*self.clock, t));
+                    new_deadline = self.deadline.is_some();
+                }
+                Some(Action::Wait(_)) |
+                Some(Action::Idle(..)) if self.skip_waits => {
+                    // Skipped, move on to the next action
+                }
+                Some(Action::Wait(dur)) |
+                Some(Action::Idle(dur, _)) if self.virtual_time => {
+                    // Completes immediately, move on to the next action
+                    let dur = self.wait_duration(dur);
+                    handle::lock(&self.shared).virtual_elapsed += dur;
+                }
+                Some(Action::Wait(dur)) => {
+                    let dur = self.wait_duration(dur);
+
+                    #[cfg(feature = "tracing")]
+                    self.traced(|| tracing::debug!(duration = ?dur, "waiting"));
+
+                    let mut sleep = self.clock.sleep(dur);
+
+                    // Poll, if ready, yield
+                    if sleep.poll().unwrap().is_ready() {
+                        task::current().notify();
+                    }
+
+                    self.state = Some(State::Waiting(sleep, dur));
+                }
+                Some(Action::Idle(dur, period)) => {
+                    let dur = self.wait_duration(dur);
+                    // Scaled like the duration, but never so short that
+                    // ticks are always due
+                    let period = from_secs_f64(as_secs_f64(period) * self.time_scale);
+                    let period = cmp::max(period, Duration::from_millis(1));
+
+                    let mut idle = Idle {
+                        end: self.clock.sleep(dur),
+                        tick: self.clock.sleep(period),
+                        period: period,
+                        duration: dur,
+                    };
+
+                    // Poll both, so that the task is woken by whichever
+                    // fires first
+                    if idle.end.poll().unwrap().is_ready() {
+                        task::current().notify();
+                    } else {
+                        idle.tick.poll().unwrap();
+                    }
+
+                    self.state = Some(State::Idle(idle));
+                }
+                Some(Action::Silence(dur)) => {
+                    let mut sleep = self.clock.sleep(dur);
+
+                    if sleep.poll().unwrap().is_ready() {
+                        task::current().notify();
+                    }
+
+                    self.state = Some(State::Silent(sleep, dur));
+                }
+                Some(Action::ReadError(kind)) => {
+                    self.state = Some(State::ReadError(Some(kind)));
+                }
+                Some(Action::WriteError(kind)) => {
+                    self.state = Some(State::WriteError(Some(kind)));
+                }
+                Some(Action::Error(kind)) => {
+                    self.state = Some(State::Error(Some(kind)));
+                }
+                Some(Action::Eof) => {
+                    self.state = Some(State::Eof(false));
+                }
+                Some(Action::Reset) => {
+                    self.state = Some(State::Reset(false));
+                }
+                Some(Action::Shutdown) => {
+                    self.state = Some(State::Shutdown(false));
+                }
+                Some(Action::Flush) => {
+                    self.state = Some(State::Flush(false));
+                }
+                Some(Action::PeerClose(accept)) => {
+                    self.state = Some(State::PeerClosed(accept));
+                }
+                Some(Action::LingerClose) => {
+                    // Completes immediately, move on to the next action. The
+                    // indices of the dropped writes are kept, so that later
+                    // actions keep their place in the script.
+                    self.write_closed = true;
+
+                    let mut index = self.next_index();
+                    let mut kept = VecDeque::new();
+
+                    for action in self.actions.drain(..) {
+                        if action.is_write() {
+                            self.skipped.insert(index);
+                        } else {
+                            kept.push_back(action);
+                        }
+
+                        index += 1;
+
+                        while self.skipped.contains(&index) {
+                            index += 1;
+                        }
+                    }
+
+                    self.actions = kept;
+                }
+                Some(Action::Assert(f)) => {
+                    // Completes immediately, move on to the next action
+                    let elapsed = self.elapsed();
+                    let state = handle::state(&self.shared, elapsed);
+
+                    if !(&mut *f.0.lock().unwrap())(&state) {
+                        panic!("assertion failed ({}); state={:?}; {}",
+                               self.action_label(), state, self.neighborhood());
+                    }
+                }
+                Some(Action::Checkpoint(label)) => {
+                    // Completes immediately, move on to the next action. The
+                    // stream may have been dropped, which is fine.
+                    let _ = self.checkpoint_tx.unbounded_send(label);
+                }
+                Some(Action::WaitFor(Gate(mut signal))) => {
+                    // The signal must not be polled again once complete, so
+                    // move straight on if it already fired
+                    if !signal.poll().unwrap().is_ready() {
+                        self.state = Some(State::WaitingFor(signal));
+                    }
+                }
+                Some(Action::Named(..)) |
+                Some(Action::Within(..)) |
+                Some(Action::WithinAfterRead(..)) |
+                Some(Action::Chunked(..)) => unreachable!(),
+                None => break,
+            }
+
+            if started && self.state.is_none() {
+                // The action completed immediately
+                self.complete_action();
+            }
+
+            if self.state.is_some() {
+                if let Some(timeout) = timeout {
+                    let mut remaining = timeout;
+
+                    if after_read {
+                        if let Some(completed) = self.read_completed {
+                            let elapsed = self.clock.now().duration_since(completed);
+                            remaining = timeout.checked_sub(elapsed).unwrap_or(Duration::from_secs(0));
+                        }
+                    }
+
+                    self.deadline = Some(Deadline {
+                        sleep: self.clock.sleep(remaining),
+                        timeout: timeout,
+                    });
+                    new_deadline = true;
+                }
+            }
+        }
+
+        // Poll a new deadline right away, so that the task is woken once it
+        // expires
+        if new_deadline && self.is_deadline_expired() {
+            self.fail_deadline();
+            advanced = true;
+        }
+
+        if self.poll_script_deadline() {
+            advanced = true;
+        }
+
+        if advanced {
+            self.publish_progress();
+        }
+
+        self.state.as_mut()
+    }
+
+    /// Counts a `read` or `write` call against the current action
+    fn count_poll(&mut self) {
+        if self.state().is_none() {
+            return;
+        }
+
+        let count = handle::record_poll(&self.shared, self.next_action - 1);
+
+        if let Some(max) = self.max_polls {
+            if count > max {
+                panic!("{} polled {} times, more than the limit of {}; state={:?}; {}",
+                       self.action_label(), count, max, self.state, self.neighborhood());
+            }
+        }
+    }
+
+    /// Summarizes the run so far
+    fn report(&self) -> FixtureReport {
+        let (current, remaining) = self.progress(usize::MAX);
+        let now = self.timestamp();
+        let shared = handle::lock(&self.shared);
+
+        // Stop the clock once the script completed
+        let elapsed = match shared.timeline.actions().last() {
+            Some(entry) if current.is_none() && remaining.is_empty() => {
+                entry.completed().unwrap_or(now)
+            }
+            _ => now,
+        };
+
+        completion::report(current,
+                           remaining,
+                           self.failure.clone(),
+                           self.mismatches.clone(),
+                           shared.bytes_read,
+                           shared.bytes_written,
+                           shared.divergence.clone(),
+                           self.unread(),
+                           self.polled,
+                           elapsed,
+                           shared.timeline.clone())
+    }
+
+    /// Returns the index of the current action and the number of bytes left
+    /// to read, if it is a read that has not been fully read
+    fn unread(&self) -> Option<(usize, usize)> {
+        match self.state {
+            Some(State::Reading(ref buf)) if buf.has_remaining() => {
+                Some((self.next_action - 1, buf.remaining()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Records where the run stopped, if the script did not complete, and
+    /// reports the outcome to the completion future
+    fn conclude(&mut self, reason: &str) -> FixtureReport {
+        if self.remaining_actions() > 0 {
+            let index = if self.state.is_some() && !self.is_current_action_done() {
+                self.next_action - 1
+            } else {
+                self.next_index()
+            };
+
+            let reason = match self.unread() {
+                _ if !self.polled => format!("{} without being polled", reason),
+                Some((_, len)) => format!("{} with {} bytes left unread", reason, len),
+                None => reason.into(),
+            };
+
+            self.diverge(index, reason, vec![]);
+        }
+
+        let report = self.report();
+
+        #[cfg(feature = "tracing")]
+        tracing::info!(complete = report.is_complete(), report = ?report, "fixture finished");
+
+        handle::lock(&self.shared).report = Some(report.clone());
+
+        for tx in self.completion_txs.drain(..) {
+            let _ = tx.send(report.clone().into_result());
+        }
+
+        report
+    }
+
+    /// Returns the time since the fixture was created, or `None` when running
+    /// in virtual time, where the virtual clock is used instead
+    fn elapsed(&self) -> Option<Duration> {
+        if self.virtual_time {
+            None
+        } else {
+            Some(self.clock.now().duration_since(self.created))
+        }
+    }
+
+    /// Records the start of the current action
+    fn start_action(&mut self, description: String) {
+        let index = self.next_action - 1;
+        let now = self.timestamp();
+        let written = handle::lock(&self.shared).bytes_written;
+
+        self.tracker.start(index, description.clone(), now, written);
+
+        {
+            let mut shared = handle::lock(&self.shared);
+            let name = self.action_name.clone();
+            let phase = self.action_phase.clone();
+            timeline::start(&mut shared.timeline, index, name, phase, description.clone(), now);
+        }
+
+        #[cfg(feature = "log")]
+        {
+            if self.log {
+                debug!("started {}: {}", self.action_label(), description);
+            }
+        }
+
+        #[cfg(feature = "tracing")]
+        {
+            self.span = Some(tracing::debug_span!("action",
+                                                  index = index,
+                                                  name = ?self.action_name,
+                                                  phase = ?self.action_phase,
+                                                  description = %description));
+        }
+
+        self.emit(FixtureEvent::ActionStarted {
+            index: index,
+            name: self.action_name.clone(),
+            description: description,
+        });
+    }
+
+    /// Records the completion of the current action
+    fn complete_action(&mut self) {
+        let now = self.timestamp();
+        self.tracker.complete(now);
+        timeline::complete(&mut handle::lock(&self.shared).timeline, now);
+
+        let index = self.next_action - 1;
+        self.emit(FixtureEvent::ActionCompleted { index: index });
+
+        #[cfg(feature = "log")]
+        {
+            if self.log {
+                debug!("completed {}", self.action_label());
+            }
+        }
+
+        #[cfg(feature = "tracing")]
+        {
+            self.traced(|| tracing::debug!("action completed"));
+            self.span = None;
+        }
+    }
+
+    /// Records where the run diverged from the script, unless it already has
+    fn diverge(&mut self, index: usize, reason: String, expected: Vec<u8>) {
+        let now = self.timestamp();
+        let mut shared = handle::lock(&self.shared);
+
+        if shared.divergence.is_some() {
+            return;
+        }
+
+        let written = shared.history.bytes();
+        let name = self.action_name.clone();
+        let phase = self.phase_of(index);
+        let divergence = self.tracker.diverge(index, name, phase, reason, expected, &written, now);
+
+        shared.divergence = Some(divergence);
+    }
+
+    /// Returns the time since the fixture was created, or the virtual time
+    /// when running in virtual time
+    fn timestamp(&self) -> Duration {
+        match self.elapsed() {
+            Some(elapsed) => elapsed,
+            None => handle::lock(&self.shared).virtual_elapsed,
+        }
+    }
+
+    /// Runs `f`, which emits tracing events, in the span of the current action
+    #[cfg(feature = "tracing")]
+    fn traced<F: FnOnce()>(&self, f: F) {
+        match self.span {
+            Some(ref span) => span.in_scope(f),
+            None => f(),
+        }
+    }
+
+    /// Reports an event to the observers and to the stream returned by
+    /// `events`, if any
+    fn emit(&mut self, event: FixtureEvent) {
+        for observer in &mut self.observers {
+            match event {
+                FixtureEvent::ActionStarted { index, ref description, .. } => {
+                    observer.on_action_start(index, description);
+                }
+                FixtureEvent::ActionCompleted { index } => {
+                    observer.on_action_complete(index);
+                }
+                FixtureEvent::Mismatch(ref m) => {
+                    observer.on_mismatch(m);
+                }
+                FixtureEvent::Eof => {}
+            }
+        }
+
+        #[cfg(feature = "tracing")]
+        match event {
+            FixtureEvent::Mismatch(ref m) => {
+                self.traced(|| tracing::warn!(mismatch = %m, "write did not match the script"));
+            }
+            FixtureEvent::Eof => {
+                self.traced(|| tracing::debug!("eof"));
+            }
+            _ => {}
+        }
+
+        #[cfg(feature = "log")]
+        {
+            if let FixtureEvent::Mismatch(ref m) = event {
+                if self.log {
+                    warn!("{}", m);
+                }
+            }
+        }
+
+        if let Some(ref tx) = self.events_tx {
+            // The stream may have been dropped, which is fine
+            let _ = tx.unbounded_send(event);
+        }
+    }
+
+    /// Describes the current action and up to `limit` of the actions after
+    /// it
+    fn progress(&self, limit: usize) -> (Option<String>, Vec<String>) {
+        let max = self.debug_preview;
+        let mut actions = self.actions.iter();
+
+        let current = match self.state {
+            Some(ref state) if !self.is_current_action_done() => {
+                let state = StatePreview { state: state, max: max };
+                Some(format!("{}: {:?}", self.action_label(), state))
+            }
+            // Before the fixture is first used, or once the current action
+            // is done, the next action is current
+            _ => actions.next().map(|action| {
+                let action = ActionPreview { action: action, max: max };
+                format!("action {}: {:?}", self.next_index(), action)
+            }),
+        };
+
+        let remaining = actions
+            .take(limit)
+            .map(|action| format!("{:?}", ActionPreview { action: action, max: max }))
+            .collect();
+
+        (current, remaining)
+    }
+
+    /// Publishes the progress once the last action completes, which is
+    /// otherwise only noticed on the next call
+    fn publish_if_done(&self) {
+        if self.actions.is_empty() && self.is_current_action_done() {
+            self.publish_progress();
+        }
+    }
+
+    /// Makes the current action, and the first of the remaining ones,
+    /// available to handles
+    fn publish_progress(&self) {
+        let (current, remaining) = self.progress(PROGRESS_PREVIEW);
+        let count = self.remaining_actions();
+
+        let mut shared = handle::lock(&self.shared);
+        shared.current_action = current;
+        shared.remaining_actions = remaining;
+        shared.actions_remaining = count;
+    }
+
+    /// Fails the fixture if the script did not complete before its deadline,
+    /// returning true if it did so
+    fn poll_script_deadline(&mut self) -> bool {
+        let timeout = match self.script_timeout {
+            Some(timeout) => timeout,
+            None => return false,
+        };
+
+        if self.state.is_none() && self.actions.is_empty() {
+            // The script completed in time
+            self.script_timeout = None;
+            self.script_deadline = None;
+            return false;
+        }
+
+        if self.script_deadline.is_none() {
+            self.script_deadline = Some(Deadline::new(&*self.clock, timeout));
+        }
+
+        if !self.script_deadline.as_mut().unwrap().sleep.poll().unwrap().is_ready() {
+            return false;
+        }
+
+        self.script_timeout = None;
+        self.script_deadline = None;
+        self.deadline = None;
+
+        let msg = format!("script did not complete within {:?}; stalled at {}: {:?}; {}",
+                          timeout, self.action_label(), self.state, self.neighborhood());
+
+        let index = self.next_action - 1;
+        self.diverge(index, msg.clone(), vec![]);
+        self.failure = Some(msg.clone());
+        self.fail(io::ErrorKind::TimedOut, msg);
+
+        if let Some(task) = self.read_wait.take() {
+            task.notify();
+        }
+
+        true
+    }
+
+    /// Returns how long to wait for a scripted wait of `dur`
+    fn wait_duration(&mut self, dur: Duration) -> Duration {
+        let mut secs = as_secs_f64(dur) * self.time_scale;
+
+        if let Some(ref mut jitter) = self.jitter {
+            secs *= 1.0 + jitter.fraction * (2.0 * jitter.rng.next_f64() - 1.0);
+        }
+
+        let dur = from_secs_f64(secs.max(0.0));
+
+        match self.max_wait {
+            Some(max) => cmp::min(dur, max),
+            None => dur,
+        }
+    }
+
+    fn is_current_action_complete(&mut self) -> bool {
+        match self.state {
+            Some(State::Waiting(ref mut sleep, _)) |
+            Some(State::Silent(ref mut sleep, _)) |
+            Some(State::WaitingFor(ref mut sleep)) => {
+                sleep.poll().unwrap().is_ready()
+            }
+            Some(State::Idle(ref mut idle)) => {
+                if idle.end.poll().unwrap().is_ready() {
+                    return true;
+                }
+
+                // Start the next tick once the current one has woken the task
+                while idle.tick.poll().unwrap().is_ready() {
+                    idle.tick = self.clock.sleep(idle.period);
+                }
+
+                false
+            }
+            // The connection stays closed, so the script never moves on
+            Some(ref state) if state.is_terminal() => false,
+            _ => self.is_current_action_done(),
+        }
+    }
+
+    /// Returns true if the current action has completed, without polling
+    /// timers or signals, so that it may be called outside of a task. Waits
+    /// are treated as incomplete.
+    fn is_current_action_done(&self) -> bool {
+        match self.state {
+            Some(State::Reading(ref buf)) => {
+                !buf.has_remaining()
+            }
+            Some(State::Writing(ref matcher)) => {
+                matcher.is_complete()
+            }
+            Some(State::ReadError(ref kind)) |
+            Some(State::WriteError(ref kind)) |
+            Some(State::Error(ref kind)) => {
+                kind.is_none()
+            }
+            Some(State::Eof(done)) |
+            Some(State::Reset(done)) |
+            Some(State::Shutdown(done)) |
+            Some(State::Flush(done)) => done,
+            // Done once the read side has seen the close
+            Some(State::PeerClosed(..)) => self.read_eof,
+            _ => false,
+        }
+    }
+
+    fn is_deadline_expired(&mut self) -> bool {
+        match self.deadline {
+            Some(ref mut deadline) => deadline.sleep.poll().unwrap().is_ready(),
+            None => false,
+        }
+    }
+
+    /// Moves the current action to `State::Failed`. A read or write in
+    /// progress becomes the current action, so that `snapshot` can replay
+    /// what remains of it.
+    fn fail(&mut self, kind: io::ErrorKind, msg: String) {
+        match mem::replace(&mut self.state, Some(State::Failed(kind, msg))) {
+            Some(State::Reading(buf)) => {
+                let pos = buf.position() as usize;
+                self.current = Some(Action::Read(buf.into_inner().split_off(pos)));
+            }
+            Some(State::Writing(matcher)) => {
+                self.current = Some(Action::Write(matcher));
+            }
+            _ => {}
+        }
+    }
+
+    /// Fails the fixture because the current action missed its deadline
+    fn fail_deadline(&mut self) {
+        let timeout = self.deadline.take().unwrap().timeout;
+
+        let msg = match self.state {
+            Some(State::Writing(ref matcher)) => {
+                format!("expected write {:?} not received within {:?}", matcher, timeout)
+            }
+            Some(ref state) => {
+                format!("{:?} did not complete within {:?}", state, timeout)
+            }
+            None => unreachable!(),
+        };
+
+        let msg = format!("{}: {}; {}", self.action_label(), msg, self.neighborhood());
+
+        let index = self.next_action - 1;
+        self.diverge(index, msg.clone(), vec![]);
+        self.failure = Some(msg.clone());
+        self.fail(io::ErrorKind::TimedOut, msg);
+
+        if let Some(task) = self.read_wait.take() {
+            task.notify();
+        }
+    }
+
+    /// Identifies the current action in failure messages
+    fn action_label(&self) -> String {
+        let label = match self.action_name {
+            Some(ref name) => format!("action {} ({})", self.next_action - 1, name),
+            None => format!("action {}", self.next_action - 1),
+        };
+
+        match self.action_phase {
+            Some(ref phase) => format!("{} in phase {}", label, phase),
+            None => label,
+        }
+    }
+
+    /// Describes the actions before and after the current one, to help find
+    /// it in a long script
+    fn neighborhood(&self) -> String {
+        let previous = match self.tracker.previous() {
+            Some(action) => {
+                format!("action {}: {}",
+                        action.index(), truncate(action.description(), CONTEXT_PREVIEW))
+            }
+            None => "none".into(),
+        };
+
+        let next = match self.actions.front() {
+            Some(action) => {
+                let action = ActionPreview { action: action, max: self.debug_preview };
+                format!("action {}: {}", self.next_index(),
+                        truncate(&format!("{:?}", action), CONTEXT_PREVIEW))
+            }
+            None => "none".into(),
+        };
+
+        format!("previous={}; next={}", previous, next)
+    }
+
+    /// Returns the index within the script of the action at the front of the
+    /// queue
+    fn next_index(&self) -> usize {
+        let mut index = self.next_action;
+
+        while self.skipped.contains(&index) {
+            index += 1;
+        }
+
+        index
+    }
+
+    /// Returns the names of the phases containing the action at `index`,
+    /// joined with `/`
+    fn phase_of(&self, index: usize) -> Option<String> {
+        let names = self.phases.iter()
+            .filter(|phase| phase.actions.start <= index && index < phase.actions.end)
+            .map(|phase| &phase.name[..])
+            .collect::<Vec<_>>();
+
+        if names.is_empty() {
+            None
+        } else {
+            Some(names.join("/"))
+        }
+    }
+
+    fn reset_faults(&mut self) {
+        self.interrupts_remaining = self.spurious_interrupts;
+        self.blocks_remaining = self.spurious_blocks;
+    }
+
+    /// Returns an error if a fault should be injected before transferring data
+    /// for the current action. Otherwise, returns the number of bytes, out of
+    /// `len`, that may be transferred.
+    fn inject_fault(&mut self, len: usize, read: bool) -> io::Result<usize> {
+        if self.interrupts_remaining > 0 {
+            self.interrupts_remaining -= 1;
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+        }
+
+        if self.blocks_remaining > 0 {
+            self.blocks_remaining -= 1;
+            task::current().notify();
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+        }
+
+        if let Some(ref mut faults) = self.faults {
+            if faults.rng.chance(faults.config.interrupted) {
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+            }
+
+            if faults.rng.chance(faults.config.would_block) {
+                task::current().notify();
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+            }
+
+            let short = if read {
+                faults.config.short_read
+            } else {
+                faults.config.short_write
+            };
+
+            if len > 1 && faults.rng.chance(short) {
+                return Ok(1 + faults.rng.below(len - 1));
+            }
+        }
+
+        Ok(len)
+    }
+
+    /// Notifies the current task, which is blocked, if a spurious wakeup
+    /// remains for the current action
+    fn maybe_spurious_wakeup(&mut self) {
+        if self.wakeups_remaining > 0 {
+            self.wakeups_remaining -= 1;
+            task::current().notify();
+        }
+    }
+
+    fn maybe_wakeup_reader(&mut self) {
+        let readable = match self.state() {
+            Some(state) => state.is_reading(),
+            None => true,
+        };
+
+        if readable {
+            if let Some(task) = self.read_wait.take() {
+                task.notify();
+            }
+        }
+    }
+
+    fn poll_read(&mut self) -> Async<()> {
+        let ret = match self.state() {
+            Some(ref state) if state.is_reading() => {
+                Async::Ready(())
+            }
+            Some(_) => {
+                Async::NotReady
+            }
+            None => {
+                Async::Ready(())
+            }
+        };
+
+        if !ret.is_ready() {
+            self.read_wait = Some(task::current());
+        }
+
+        ret
+    }
+}
+
+impl io::Read for FixtureIo {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        let ret = self.read_script(dst);
+        self.publish_if_done();
+
+        let data = match ret {
+            Ok(n) => &dst[..n],
+            Err(_) => &[],
+        };
+
+        let elapsed = self.elapsed();
+        handle::record_call(&self.shared, Direction::Read, data, &ret, elapsed, self.call_history);
+
+        if is_would_block(&ret) {
+            handle::block(&self.shared);
+        }
+
+        #[cfg(feature = "tracing")]
+        self.traced(|| tracing::debug!(result = ?ret, data = ?data, "read"));
+
+        #[cfg(feature = "log")]
+        {
+            if self.log {
+                debug!("read; result={:?}; data={:?}", ret, data);
+            }
+        }
+
+        for observer in &mut self.observers {
+            observer.on_read(data, &ret);
+        }
+
+        if let Some(ref mut recorder) = self.recorder {
+            if !data.is_empty() {
+                if let Err(e) = recorder.read(data) {
+                    panic!("failed to record read; err={}", e);
+                }
+            }
+        }
+
+        ret
+    }
+}
+
+impl FixtureIo {
+    fn read_script(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        self.polled = true;
+
+        if let Some(kind) = handle::aborted(&self.shared) {
+            return Err(io::Error::new(kind, "aborted"));
+        }
+
+        if handle::poll_paused(&self.shared) {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+        }
+
+        if self.read_eof {
+            return match self.eof_policy {
+                EofPolicy::Repeat => Ok(0),
+                EofPolicy::BrokenPipe => {
+                    Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"))
+                }
+            };
+        }
+
+        let ready = self.poll_read().is_ready();
+
+        self.count_poll();
+
+        if !ready {
+            self.maybe_spurious_wakeup();
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+        }
+
+        let mut limit = cmp::min(dst.len(), self.max_read_chunk.unwrap_or(dst.len()));
+
+        if let Some(&mut State::Reading(..)) = self.state() {
+            if let Some(ref mut fragments) = self.fragments {
+                if !fragments.poll().is_ready() {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+                }
+
+                limit = cmp::min(limit, fragments.size);
+            }
+
+            limit = try!(self.inject_fault(limit, true));
+
+            if let Some(ref mut throttle) = self.read_rate {
+                match throttle.poll(&*self.clock) {
+                    Async::Ready(n) => limit = cmp::min(limit, n),
+                    Async::NotReady => {
+                        return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+                    }
+                }
+            }
+        }
+
+        let n = match self.state() {
+            Some(&mut State::Reading(ref mut buf)) => {
+                let n = cmp::min(limit, buf.remaining());
+                buf.copy_to_slice(&mut dst[..n]);
+                n
+            }
+            Some(&mut State::ReadError(ref mut kind)) |
+            Some(&mut State::Error(ref mut kind)) => {
+                let kind = kind.take().unwrap();
+                return Err(kind.into());
+            }
+            Some(&mut State::Eof(ref mut done)) => {
+                *done = true;
+                self.read_eof = true;
+                self.emit(FixtureEvent::Eof);
+                return Ok(0);
+            }
+            Some(&mut State::Failed(kind, ref msg)) => {
+                return Err(io::Error::new(kind, msg.clone()));
+            }
+            Some(&mut State::Reset(ref mut delivered)) => {
+                *delivered = true;
+                return Err(connection_reset());
+            }
+            Some(&mut State::PeerClosed(..)) => {
+                self.read_eof = true;
+                self.emit(FixtureEvent::Eof);
+                return Ok(0);
+            }
+            None => {
+                if !self.rules.is_active() {
+                    if !self.wait_for_pushed {
+                        return Ok(0);
+                    }
+
+                    // Actions pushed since the script was advanced are
+                    // picked up by the next call
+                    if !handle::wait_pushed(&self.shared) {
+                        task::current().notify();
+                    }
+
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+                }
+
+                match self.rules.read(&mut dst[..limit]) {
+                    Some(n) => n,
+                    None => {
+                        self.read_wait = Some(task::current());
+                        return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+                    }
+                }
+            }
+            _ => {
+                unreachable!();
+            }
+        };
+
+        if let Some(ref mut throttle) = self.read_rate {
+            throttle.consume(n);
+        }
+
+        if let Some(State::Reading(ref buf)) = self.state {
+            if buf.has_remaining() {
+                // Pause before the next fragment of the current read action
+                if let Some(ref mut fragments) = self.fragments {
+                    fragments.pause = Some((self.clock.sleep(fragments.delay), false));
+                }
+            } else {
+                self.read_completed = Some(self.clock.now());
+            }
+        }
+
+        if n > 0 {
+            handle::record_read(&self.shared, &dst[..n]);
+        }
+
+        self.maybe_wakeup_reader();
+
+        Ok(n)
+    }
+}
+
+impl AsyncRead for FixtureIo {
+}
+
+impl FixtureIo {
+    fn write_script(&mut self, src: &[u8]) -> io::Result<usize> {
+        self.polled = true;
+
+        if let Some(kind) = handle::aborted(&self.shared) {
+            return Err(io::Error::new(kind, "aborted"));
+        }
+
+        if handle::poll_paused(&self.shared) {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+        }
+
+        if self.write_closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"));
+        }
+
+        self.count_poll();
+
+        let mut limit = cmp::min(src.len(), self.max_write_accept.unwrap_or(src.len()));
+
+        if let Some(&mut State::Writing(..)) = self.state() {
+            match self.write_chunk {
+                Some((_, ref mut blocked)) if *blocked => {
+                    *blocked = false;
+                    task::current().notify();
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+                }
+                Some((max, _)) => limit = cmp::min(limit, max),
+                None => {}
+            }
+
+            limit = try!(self.inject_fault(limit, false));
+
+            if let Some(ref mut throttle) = self.write_rate {
+                match throttle.poll(&*self.clock) {
+                    Async::Ready(n) => limit = cmp::min(limit, n),
+                    Async::NotReady => {
+                        return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+                    }
+                }
+            }
+        }
+
+        let src = &src[..cmp::min(limit, src.len())];
+
+        // Set if a mismatch is reported to the caller
+        let mut failed = None;
+
+        // The state was advanced above. Match on the field directly so that the
+        // mismatch handler can be borrowed at the same time.
+        let ret = match self.state {
+            Some(State::Writing(ref mut matcher)) => {
+                match matcher.matches(src) {
+                    MatchResult::Matched(n) => {
+                        debug_assert!(n <= src.len(), "{:?} matched more than was written",
+                                      matcher);
+                        debug_assert!(n > 0 || src.is_empty() || matcher.is_complete(),
+                                      "{:?} matched no data", matcher);
+
+                        let n = cmp::min(n, src.len());
+
+                        // Block once before accepting the next chunk
+                        if let Some((_, ref mut blocked)) = self.write_chunk {
+                            *blocked = n > 0 && !matcher.is_complete();
+                        }
+
+                        Ok(n)
+                    }
+                    MatchResult::Mismatch(n, mut m) => {
+                        debug_assert!(n <= src.len(), "{:?} consumed more than was written",
+                                      matcher);
+
+                        let n = cmp::min(n, src.len());
+
+                        m.action = Some(self.next_action - 1);
+                        m.name = self.action_name.clone();
+                        m.phase = self.action_phase.clone();
+                        m.context = Some(self.neighborhood());
+                        self.mismatches.push(m.clone());
+                        self.emit(FixtureEvent::Mismatch(m.clone()));
+                        self.diverge(self.next_action - 1, m.to_string(), m.expected.clone());
+
+                        match mismatch(&mut self.on_mismatch, m) {
+                            Ok(()) => Ok(n),
+                            Err(e) => {
+                                failed = Some(e.to_string());
+                                Err(e)
+                            }
+                        }
+                    }
+                }
+            }
+            Some(State::WriteError(ref mut kind)) |
+            Some(State::Error(ref mut kind)) => {
+                let kind = kind.take().unwrap();
+                Err(kind.into())
+            }
+            Some(State::Failed(kind, ref msg)) => {
+                Err(io::Error::new(kind, msg.clone()))
+            }
+            Some(State::Reset(ref mut delivered)) => {
+                *delivered = true;
+                Err(connection_reset())
+            }
+            Some(State::PeerClosed(ref mut remaining)) => {
+                if *remaining == 0 {
+                    return Err(connection_reset());
+                }
+
+                let n = cmp::min(*remaining, src.len());
+                *remaining -= n;
+                Ok(n)
+            }
+            Some(State::Silent(..)) => {
+                // Nothing may be written
+                let mut m = Mismatch::new(0, 0, &[], src);
+                m.action = Some(self.next_action - 1);
+                m.name = self.action_name.clone();
+                m.phase = self.action_phase.clone();
+                m.context = Some(self.neighborhood());
+                self.mismatches.push(m.clone());
+                self.emit(FixtureEvent::Mismatch(m.clone()));
+                self.diverge(self.next_action - 1, m.to_string(), vec![]);
+                try!(mismatch(&mut self.on_mismatch, m));
+                Ok(src.len())
+            }
+            None if self.rules.is_active() => {
+                self.rules.write(src)
+            }
+            None => {
+                match self.extra_write {
+                    ExtraWritePolicy::Panic => {
+                        panic!("unexpected write after script completed; data={:?}", src);
+                    }
+                    ExtraWritePolicy::Error(kind) => {
+                        return Err(kind.into());
+                    }
+                    ExtraWritePolicy::Discard => {
+                        return Ok(src.len());
+                    }
+                }
+            }
+            _ => {
+                self.maybe_spurious_wakeup();
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+            }
+        };
+
+        // The matcher has consumed the data, which the caller is told was not
+        // written, so the action can't continue
+        if let Some(msg) = failed {
+            self.fail(io::ErrorKind::InvalidData, msg);
+
+            if let Some(task) = self.read_wait.take() {
+                task.notify();
+            }
+        }
+
+        if let Ok(n) = ret {
+            if let Some(ref mut throttle) = self.write_rate {
+                throttle.consume(n);
+            }
+
+            if n > 0 {
+                handle::record_write(&self.shared, &src[..n]);
+            }
+        }
+
+        self.maybe_wakeup_reader();
+
+        ret
+    }
+
+}
+
+impl io::Write for FixtureIo {
+    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        let ret = self.write_script(src);
+        self.publish_if_done();
+        let elapsed = self.elapsed();
+        handle::record_call(&self.shared, Direction::Write, src, &ret, elapsed, self.call_history);
+
+        if is_would_block(&ret) {
+            handle::block(&self.shared);
+        }
+
+        #[cfg(feature = "tracing")]
+        self.traced(|| tracing::debug!(result = ?ret, data = ?src, "write"));
+
+        #[cfg(feature = "log")]
+        {
+            if self.log {
+                debug!("write; result={:?}; data={:?}", ret, src);
+            }
+        }
+
+        for observer in &mut self.observers {
+            observer.on_write(src, &ret);
+        }
+
+        if let Some(ref mut recorder) = self.recorder {
+            if let Ok(n) = ret {
+                if n > 0 {
+                    if let Err(e) = recorder.write(&src[..n]) {
+                        panic!("failed to record write; err={}", e);
+                    }
+                }
+            }
+        }
+
+        ret
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.polled = true;
+
+        if let Some(kind) = handle::aborted(&self.shared) {
+            return Err(io::Error::new(kind, "aborted"));
+        }
+
+        if handle::poll_paused(&self.shared) {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+        }
+
+        if let Some(&mut State::Flush(ref mut done)) = self.state() {
+            *done = true;
+        }
+
+        self.maybe_wakeup_reader();
+
+        Ok(())
+    }
+}
+
+impl AsyncWrite for FixtureIo {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.polled = true;
+
+        if let Some(kind) = handle::aborted(&self.shared) {
+            return Err(io::Error::new(kind, "aborted"));
+        }
+
+        if handle::poll_paused(&self.shared) {
+            return Ok(Async::NotReady);
+        }
+
+        self.shutdown_called = true;
+
+        if let Some(&mut State::Shutdown(ref mut done)) = self.state() {
+            *done = true;
+        }
+
+        self.maybe_wakeup_reader();
+
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for FixtureIo {
     fn drop(&mut self) {
-        let _ = self.drop_tx.send(());
+        for tx in &self.drop_txs {
+            let _ = tx.send(());
+        }
+
+        if self.finished {
+            // Already reported by `finish`
+            return;
+        }
+
+        self.conclude("dropped before the script completed");
+
+        // Don't turn an earlier panic into an abort
+        if thread::panicking() {
+            return;
+        }
+
+        if self.expect_polled && !self.polled {
+            panic!("fixture dropped without being polled; was the future using it spawned?");
+        }
+
+        if self.expect_shutdown && !self.shutdown_called {
+            panic!("fixture dropped without `shutdown` being called");
+        }
+
+        if self.panic_on_incomplete {
+            if let Some((_, len)) = self.unread() {
+                panic!("fixture dropped with {} bytes of {} never read; {}",
+                       len, self.action_label(), self.neighborhood());
+            }
+
+            if self.is_current_action_done() {
+                self.state = None;
+            }
+
+            if self.state.is_some() || !self.actions.is_empty() {
+                let (current, next) = self.progress(PROGRESS_PREVIEW);
+
+                panic!("fixture dropped before the script completed; {} actions remaining; \
+                        current={}; next={:?}",
+                       self.remaining_actions(), current.unwrap_or_default(), next);
+            }
+        }
+    }
+}
+
+impl Action {
+    /// Returns the data read or written by the action, for `FixtureIo::save`.
+    /// Returns an error if the action changes how the connection behaves in
+    /// a way a dump can't represent. An `Eof` is only allowed as the `last`
+    /// action, where it is implied by the end of the dump.
+    fn data(&self, last: bool) -> io::Result<Option<(Direction, Vec<u8>)>> {
+        match *self {
+            Action::Read(ref data) => Ok(Some((Direction::Read, data.clone()))),
+            Action::ReadWith(..) => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                   "read computed when reached cannot be saved"))
+            }
+            Action::Write(ref matcher) => {
+                match matcher.expected() {
+                    Some(data) => Ok(Some((Direction::Write, data))),
+                    None => {
+                        let msg = format!("write matcher {:?} cannot be saved", matcher);
+                        Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+                    }
+                }
+            }
+            Action::Eof if last => Ok(None),
+            Action::ReadError(..) |
+            Action::WriteError(..) |
+            Action::Error(..) |
+            Action::Eof |
+            Action::Reset |
+            Action::PeerClose(..) |
+            Action::LingerClose => {
+                let msg = format!("{:?} cannot be saved", self);
+                Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+            }
+            Action::Named(_, ref action) |
+            Action::Within(_, ref action) |
+            Action::WithinAfterRead(_, ref action) |
+            Action::Chunked(_, ref action) => action.data(last),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns how long the action keeps the connection idle, for
+    /// `FixtureIo::save`
+    fn delay(&self) -> Duration {
+        match *self {
+            Action::Wait(dur) |
+            Action::Idle(dur, _) |
+            Action::Silence(dur) => dur,
+            Action::Named(_, ref action) |
+            Action::Within(_, ref action) |
+            Action::WithinAfterRead(_, ref action) |
+            Action::Chunked(_, ref action) => action.delay(),
+            _ => Duration::from_millis(0),
+        }
+    }
+
+    /// Copies the action, or returns `None` if it cannot be copied
+    fn try_clone(&self) -> Option<Action> {
+        let action = match *self {
+            Action::Read(ref data) => Action::Read(data.clone()),
+            Action::ReadWith(ref f) => Action::ReadWith(f.clone()),
+            Action::Write(ref matcher) => return matcher.box_clone().map(Action::Write),
+            Action::Wait(dur) => Action::Wait(dur),
+            Action::Idle(dur, tick) => Action::Idle(dur, tick),
+            Action::Silence(dur) => Action::Silence(dur),
+            Action::ReadError(kind) => Action::ReadError(kind),
+            Action::WriteError(kind) => Action::WriteError(kind),
+            Action::Error(kind) => Action::Error(kind),
+            Action::Eof => Action::Eof,
+            Action::Reset => Action::Reset,
+            Action::Shutdown => Action::Shutdown,
+            Action::Flush => Action::Flush,
+            Action::PeerClose(n) => Action::PeerClose(n),
+            Action::LingerClose => Action::LingerClose,
+            Action::Named(ref name, ref action) => {
+                return action.try_clone().map(|a| Action::Named(name.clone(), Box::new(a)));
+            }
+            Action::Within(dur, ref action) => {
+                return action.try_clone().map(|a| Action::Within(dur, Box::new(a)));
+            }
+            Action::WithinAfterRead(dur, ref action) => {
+                return action.try_clone().map(|a| Action::WithinAfterRead(dur, Box::new(a)));
+            }
+            Action::Chunked(max, ref action) => {
+                return action.try_clone().map(|a| Action::Chunked(max, Box::new(a)));
+            }
+            Action::Assert(ref f) => Action::Assert(f.clone()),
+            // The external event only happens once
+            Action::WaitFor(..) => return None,
+            Action::Checkpoint(ref label) => Action::Checkpoint(label.clone()),
+        };
+
+        Some(action)
+    }
+
+    /// Returns true for reads and writes, whose remaining data `snapshot`
+    /// takes from their state
+    fn is_transfer(&self) -> bool {
+        match *self {
+            Action::Read(..) |
+            Action::ReadWith(..) |
+            Action::Write(..) => true,
+            Action::Named(_, ref action) |
+            Action::Within(_, ref action) |
+            Action::WithinAfterRead(_, ref action) |
+            Action::Chunked(_, ref action) => action.is_transfer(),
+            _ => false,
+        }
+    }
+
+    fn is_write(&self) -> bool {
+        match *self {
+            Action::Write(..) |
+            Action::WriteError(..) |
+            Action::Flush => true,
+            Action::Named(_, ref action) |
+            Action::Within(_, ref action) |
+            Action::WithinAfterRead(_, ref action) |
+            Action::Chunked(_, ref action) => action.is_write(),
+            _ => false,
+        }
     }
 }
 
 impl State {
+    /// Returns true if the state lasts for the rest of the script, even once
+    /// done
+    fn is_terminal(&self) -> bool {
+        match *self {
+            State::Reset(..) |
+            State::PeerClosed(..) => true,
+            _ => false,
+        }
+    }
+
     fn is_reading(&self) -> bool {
         match *self {
-            State::Reading(..) => true,
+            State::Reading(..) |
+            State::ReadError(..) |
+            State::Error(..) |
+            State::Eof(..) |
+            State::Failed(..) |
+            State::Reset(..) |
+            State::PeerClosed(..) => true,
             _ => false,
         }
     }
 }
 
+impl WriteRule {
+    /// Expects the rule to be used exactly `n` times, checked by
+    /// `FixtureIo::verify`
+    pub fn times(mut self, n: usize) -> Self {
+        self.rule.set_calls(n, Some(n));
+        self
+    }
+
+    /// Expects the rule to be used at least `n` times, checked by
+    /// `FixtureIo::verify`
+    pub fn at_least(mut self, n: usize) -> Self {
+        self.rule.set_calls(n, None);
+        self
+    }
+
+    /// Queues `response` for reading each time the request is written
+    pub fn respond<T: Into<Vec<u8>>>(self, response: T) -> FixtureIo {
+        let mut io = self.io;
+        io.rules.push(self.rule, response.into());
+        io
+    }
+}
+
+impl Deadline {
+    fn new(clock: &Clock, timeout: Duration) -> Deadline {
+        Deadline {
+            sleep: clock.sleep(timeout),
+            timeout: timeout,
+        }
+    }
+}
+
+impl Fragments {
+    /// Returns `NotReady` while pausing between fragments
+    fn poll(&mut self) -> Async<()> {
+        let ready = match self.pause {
+            Some((ref mut sleep, ref mut blocked)) => {
+                let ready = sleep.poll().unwrap().is_ready();
+
+                if *blocked {
+                    ready
+                } else {
+                    // Always block once, even if the delay has passed
+                    *blocked = true;
+
+                    if ready {
+                        task::current().notify();
+                    }
+
+                    false
+                }
+            }
+            None => true,
+        };
+
+        if ready {
+            self.pause = None;
+            Async::Ready(())
+        } else {
+            Async::NotReady
+        }
+    }
+}
+
+impl Throttle {
+    fn new(bytes_per_sec: u64, burst: u64) -> Throttle {
+        assert!(bytes_per_sec > 0, "rate must be greater than zero");
+
+        let burst = cmp::max(burst, 1) as f64;
+
+        // Start with a full bucket, so the first transfer may use the burst
+        Throttle {
+            rate: bytes_per_sec as f64,
+            burst: burst,
+            tokens: burst,
+            last: None,
+            sleep: None,
+        }
+    }
+
+    /// Returns the number of bytes that may be transferred now. If none may
+    /// be, the current task is notified once at least one byte may be.
+    fn poll(&mut self, clock: &Clock) -> Async<usize> {
+        loop {
+            let now = clock.now();
+            let elapsed = match self.last {
+                Some(last) => as_secs_f64(now.duration_since(last)),
+                None => 0.0,
+            };
+
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+            self.last = Some(now);
+
+            if self.tokens >= 1.0 {
+                self.sleep = None;
+                return Async::Ready(self.tokens as usize);
+            }
+
+            if self.sleep.is_none() {
+                let wait = (1.0 - self.tokens) / self.rate;
+                self.sleep = Some(clock.sleep(from_secs_f64(wait)));
+            }
+
+            if self.sleep.as_mut().unwrap().poll().unwrap().is_ready() {
+                // The timer may round the sleep, so ensure progress
+                self.sleep = None;
+                self.tokens = self.tokens.max(1.0);
+            } else {
+                return Async::NotReady;
+            }
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.tokens = (self.tokens - n as f64).max(0.0);
+    }
+}
+
+impl Default for FaultConfig {
+    fn default() -> FaultConfig {
+        FaultConfig {
+            would_block: 0.1,
+            interrupted: 0.1,
+            short_read: 0.1,
+            short_write: 0.1,
+        }
+    }
+}
+
+impl Dist {
+    fn sample(&self, rng: &mut Rng) -> Duration {
+        // Avoid zero, which has no logarithm
+        let mut uniform = || 1.0 - rng.next_f64();
+
+        let secs = match *self {
+            Dist::Normal { mean, std_dev } => {
+                // Box-Muller transform
+                let z = (-2.0 * uniform().ln()).sqrt() *
+                    (2.0 * ::std::f64::consts::PI * uniform()).cos();
+
+                as_secs_f64(mean) + z * as_secs_f64(std_dev)
+            }
+            Dist::Exponential { mean } => {
+                -as_secs_f64(mean) * uniform().ln()
+            }
+            Dist::Pareto { scale, shape } => {
+                assert!(shape > 0.0, "shape must be greater than zero");
+                as_secs_f64(scale) / uniform().powf(1.0 / shape)
+            }
+        };
+
+        from_secs_f64(secs.max(0.0))
+    }
+}
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // Scramble the seed (splitmix64) so that small seeds, including zero,
+        // produce a usable state
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+
+        Rng { state: if z == 0 { 1 } else { z } }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Returns a value in `[0, 1)`
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns `true` with probability `p`
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && self.next_f64() < p
+    }
+
+    /// Returns a value in `[0, n)`
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// Shortens `s` to `max` characters, marking where it was cut
+fn truncate(s: &str, max: usize) -> String {
+    match s.char_indices().nth(max) {
+        Some((i, _)) => format!("{}...", &s[..i]),
+        None => s.to_string(),
+    }
+}
+
+fn as_secs_f64(dur: Duration) -> f64 {
+    dur.as_secs() as f64 + dur.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+fn from_secs_f64(secs: f64) -> Duration {
+    let whole = secs.trunc();
+    Duration::new(whole as u64, ((secs - whole) * 1_000_000_000.0) as u32)
+}
+
+impl Mismatch {
+    /// Returns a new `Mismatch` for the bytes that were compared.
+    ///
+    /// `index` is the position of the first difference between `expected` and
+    /// `actual`, and `base` is the offset of both within the write action.
+    pub fn new(base: usize, index: usize, expected: &[u8], actual: &[u8]) -> Mismatch {
+        Mismatch {
+            base: base,
+            index: index,
+            expected: expected.to_vec(),
+            actual: actual.to_vec(),
+            action: None,
+            name: None,
+            phase: None,
+            context: None,
+        }
+    }
+
+    /// Index of the write action within the script, counting from zero
+    pub fn action(&self) -> Option<usize> {
+        self.action
+    }
+
+    /// Name given to the write action with `FixtureIo::named`
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|name| &name[..])
+    }
+
+    /// Phase containing the write action, added with `FixtureIo::phase`
+    pub fn phase(&self) -> Option<&str> {
+        self.phase.as_ref().map(|phase| &phase[..])
+    }
+
+    /// Offset of the first differing byte within the write action
+    pub fn offset(&self) -> usize {
+        self.base + self.index
+    }
+
+    /// Expected bytes, starting at the first difference
+    pub fn expected(&self) -> &[u8] {
+        window(&self.expected, self.index)
+    }
+
+    /// Written bytes, starting at the first difference
+    pub fn actual(&self) -> &[u8] {
+        window(&self.actual, self.index)
+    }
+}
+
+fn window(data: &[u8], start: usize) -> &[u8] {
+    let start = cmp::min(start, data.len());
+    let end = cmp::min(data.len(), start + MISMATCH_WINDOW);
+    &data[start..end]
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(fmt, "write mismatch at offset {}", self.offset()));
+
+        if let Some(action) = self.action {
+            try!(write!(fmt, " of action {}", action));
+        }
+
+        if let Some(ref name) = self.name {
+            try!(write!(fmt, " ({})", name));
+        }
+
+        if let Some(ref phase) = self.phase {
+            try!(write!(fmt, " in phase {}", phase));
+        }
+
+        if let Some(ref context) = self.context {
+            try!(write!(fmt, "; {}", context));
+        }
+
+        // Show the row before the first difference and two rows after it
+        let len = cmp::max(self.expected.len(), self.actual.len());
+        let row = self.index / HEXDUMP_WIDTH;
+        let start = row.saturating_sub(1) * HEXDUMP_WIDTH;
+        let end = cmp::min(len, (row + 3) * HEXDUMP_WIDTH);
+
+        // Hex, ASCII and the two separators
+        let width = HEXDUMP_WIDTH * 4 + 2;
+        try!(write!(fmt, "\n{:8}  {:<w$}  {}", "offset", "expected", "actual", w = width));
+
+        let mut pos = start;
+
+        while pos < end {
+            let expected = row_of(&self.expected, pos);
+            let actual = row_of(&self.actual, pos);
+
+            try!(write!(fmt, "\n{:08x}  ", self.base + pos));
+            try!(hexdump_row(fmt, expected));
+            try!(write!(fmt, "  "));
+            try!(hexdump_row(fmt, actual));
+
+            if expected != actual {
+                try!(write!(fmt, "  <"));
+            }
+
+            pos += HEXDUMP_WIDTH;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the row of `data` starting at `pos`
+fn row_of(data: &[u8], pos: usize) -> &[u8] {
+    let start = cmp::min(pos, data.len());
+    let end = cmp::min(pos + HEXDUMP_WIDTH, data.len());
+    &data[start..end]
+}
+
+/// Writes `row` as hex followed by the printable ASCII characters, padded to
+/// the full row width
+fn hexdump_row(fmt: &mut fmt::Formatter, row: &[u8]) -> fmt::Result {
+    for i in 0..HEXDUMP_WIDTH {
+        match row.get(i) {
+            Some(b) => try!(write!(fmt, "{:02x} ", b)),
+            None => try!(write!(fmt, "   ")),
+        }
+    }
+
+    try!(write!(fmt, "|"));
+
+    for i in 0..HEXDUMP_WIDTH {
+        match row.get(i) {
+            Some(&b) if b >= 0x20 && b < 0x7f => try!(write!(fmt, "{}", b as char)),
+            Some(_) => try!(write!(fmt, ".")),
+            None => try!(write!(fmt, " ")),
+        }
+    }
+
+    write!(fmt, "|")
+}
+
+impl error::Error for Mismatch {
+    fn description(&self) -> &str {
+        "write mismatch"
+    }
+}
+
+/// Handles written data that does not match the script, returning an error if
+/// it should be reported to the caller
+fn mismatch(handler: &mut Option<MismatchHandler>, mismatch: Mismatch) -> io::Result<()> {
+    let action = match *handler {
+        Some(ref mut f) => f(&mismatch.expected, &mismatch.actual, mismatch.offset()),
+        None => MismatchAction::Panic,
+    };
+
+    match action {
+        MismatchAction::Panic => {
+            panic!("{}", mismatch);
+        }
+        MismatchAction::Error => {
+            Err(io::Error::new(io::ErrorKind::InvalidData, mismatch))
+        }
+        MismatchAction::Accept => Ok(()),
+    }
+}
+
+/// Terminates `line` with `\r\n`
+fn crlf<T: Into<Vec<u8>>>(line: T) -> Vec<u8> {
+    let mut line = line.into();
+    line.extend_from_slice(b"\r\n");
+    line
+}
+
+fn is_would_block(ret: &io::Result<usize>) -> bool {
+    match *ret {
+        Err(ref e) => e.kind() == io::ErrorKind::WouldBlock,
+        Ok(_) => false,
+    }
+}
+
+fn connection_reset() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionReset, "connection reset")
+}
+
 impl fmt::Debug for FixtureIo {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        fmt.debug_struct("FixtureIo")
-            .field("state", &self.state)
-            .field("actions", &self.actions)
+        let max = self.debug_preview;
+
+        let actions: Vec<_> = self.actions.iter()
+            .map(|action| ActionPreview { action: action, max: max })
+            .collect();
+
+        let mut fmt = fmt.debug_struct("FixtureIo");
+
+        match self.state {
+            Some(ref state) => {
+                fmt.field("state", &StatePreview { state: state, max: max });
+            }
+            None => {
+                fmt.field("state", &self.state);
+            }
+        }
+
+        fmt.field("actions", &actions)
+            .field("rules", &self.rules)
             .finish()
     }
 }
 
+impl<'a> fmt::Debug for Preview<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let head = &self.data[..cmp::min(self.max, self.data.len())];
+
+        try!(write!(fmt, "{} bytes", self.data.len()));
+
+        if head.is_empty() {
+            return Ok(());
+        }
+
+        try!(write!(fmt, ":"));
+
+        for b in head {
+            try!(write!(fmt, " {:02x}", b));
+        }
+
+        try!(write!(fmt, " |"));
+
+        for &b in head {
+            if b >= 0x20 && b < 0x7f {
+                try!(write!(fmt, "{}", b as char));
+            } else {
+                try!(write!(fmt, "."));
+            }
+        }
+
+        try!(write!(fmt, "|"));
+
+        if head.len() < self.data.len() {
+            try!(write!(fmt, " ..."));
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Debug for ActionPreview<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let preview = |action| ActionPreview { action: action, max: self.max };
+
+        match *self.action {
+            Action::Read(ref data) => {
+                write!(fmt, "Read({:?})", Preview { data: data, max: self.max })
+            }
+            Action::Write(ref matcher) if matcher.pending().is_some() => {
+                let data = matcher.pending().unwrap();
+                write!(fmt, "Write({:?})", Preview { data: data, max: self.max })
+            }
+            Action::Named(ref name, ref action) => {
+                fmt.debug_tuple("Named")
+                    .field(name)
+                    .field(&preview(action))
+                    .finish()
+            }
+            Action::Within(timeout, ref action) => {
+                fmt.debug_tuple("Within")
+                    .field(&timeout)
+                    .field(&preview(action))
+                    .finish()
+            }
+            Action::WithinAfterRead(timeout, ref action) => {
+                fmt.debug_tuple("WithinAfterRead")
+                    .field(&timeout)
+                    .field(&preview(action))
+                    .finish()
+            }
+            Action::Chunked(max, ref action) => {
+                fmt.debug_tuple("Chunked")
+                    .field(&max)
+                    .field(&preview(action))
+                    .finish()
+            }
+            ref action => action.fmt(fmt),
+        }
+    }
+}
+
+impl<'a> fmt::Debug for StatePreview<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self.state {
+            State::Reading(ref buf) => {
+                let data = &buf.get_ref()[buf.position() as usize..];
+                write!(fmt, "Reading({:?})", Preview { data: data, max: self.max })
+            }
+            State::Writing(ref matcher) if matcher.pending().is_some() => {
+                let data = matcher.pending().unwrap();
+                write!(fmt, "Writing({:?})", Preview { data: data, max: self.max })
+            }
+            ref state => state.fmt(fmt),
+        }
+    }
+}
+
+impl fmt::Debug for ReadFn {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("ReadFn")
+    }
+}
+
+impl fmt::Debug for AssertFn {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("AssertFn")
+    }
+}
+
+impl fmt::Debug for Gate {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("Gate")
+    }
+}
+
 impl fmt::Debug for State {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -287,14 +3909,74 @@ impl fmt::Debug for State {
                     .field("remaining", &buf.remaining())
                     .finish()
             }
-            State::Writing(ref buf) => {
-                fmt.debug_struct("Writing")
-                    .field("remaining", &buf.remaining())
+            State::Writing(ref matcher) => {
+                fmt.debug_tuple("Writing")
+                    .field(matcher)
                     .finish()
             }
-            State::Waiting(ref sleep) => {
+            State::Waiting(_, duration) => {
                 fmt.debug_struct("Waiting")
-                    .field("remaining", &sleep.remaining())
+                    .field("duration", &duration)
+                    .finish()
+            }
+            State::Silent(_, duration) => {
+                fmt.debug_struct("Silent")
+                    .field("duration", &duration)
+                    .finish()
+            }
+            State::ReadError(ref kind) => {
+                fmt.debug_tuple("ReadError")
+                    .field(kind)
+                    .finish()
+            }
+            State::WriteError(ref kind) => {
+                fmt.debug_tuple("WriteError")
+                    .field(kind)
+                    .finish()
+            }
+            State::Error(ref kind) => {
+                fmt.debug_tuple("Error")
+                    .field(kind)
+                    .finish()
+            }
+            State::Eof(done) => {
+                fmt.debug_struct("Eof")
+                    .field("done", &done)
+                    .finish()
+            }
+            State::Failed(kind, ref msg) => {
+                fmt.debug_struct("Failed")
+                    .field("kind", &kind)
+                    .field("msg", msg)
+                    .finish()
+            }
+            State::Reset(delivered) => {
+                fmt.debug_struct("Reset")
+                    .field("delivered", &delivered)
+                    .finish()
+            }
+            State::Shutdown(done) => {
+                fmt.debug_struct("Shutdown")
+                    .field("done", &done)
+                    .finish()
+            }
+            State::Flush(done) => {
+                fmt.debug_struct("Flush")
+                    .field("done", &done)
+                    .finish()
+            }
+            State::PeerClosed(remaining) => {
+                fmt.debug_struct("PeerClosed")
+                    .field("remaining", &remaining)
+                    .finish()
+            }
+            State::WaitingFor(_) => {
+                fmt.write_str("WaitingFor")
+            }
+            State::Idle(ref idle) => {
+                fmt.debug_struct("Idle")
+                    .field("duration", &idle.duration)
+                    .field("tick", &idle.period)
                     .finish()
             }
         }
@@ -0,0 +1,888 @@
+use {Mismatch, WriteOptions};
+use handle::{self, Shared};
+
+use std::{cmp, fmt};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+/// Checks data written to a `FixtureIo`.
+///
+/// Written data is passed to the matcher as it arrives, possibly across many
+/// `write` calls, until the matcher reports that it is complete.
+pub trait WriteMatcher: fmt::Debug + Send {
+    /// Checks the next chunk of written data.
+    ///
+    /// The number of bytes consumed must not exceed `data.len()`. Unless the
+    /// matcher is complete, at least one byte of non-empty data must be
+    /// consumed, as `write` would otherwise return `Ok(0)`.
+    fn matches(&mut self, data: &[u8]) -> MatchResult;
+
+    /// Returns true once no more data is expected
+    fn is_complete(&self) -> bool;
+
+    /// Returns a copy of the matcher, used by `FixtureIo::reset` to replay
+    /// the script. Matchers that can't be copied return `None`, the default,
+    /// and scripts using them can't be reset.
+    fn box_clone(&self) -> Option<Box<WriteMatcher>> {
+        None
+    }
+
+    /// Returns the data still expected, used by `FixtureIo::save` to write
+    /// the script to a dump. Matchers that accept varying data return
+    /// `None`, the default.
+    fn expected(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Returns the data still expected without copying it, previewed by the
+    /// `Debug` output of a fixture. Matchers that accept varying data return
+    /// `None`, the default.
+    fn pending(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+/// Result of passing written data to a `WriteMatcher`
+#[derive(Debug, Clone)]
+pub enum MatchResult {
+    /// The first `n` bytes matched and were consumed
+    Matched(usize),
+    /// The data did not match. The first `n` bytes are consumed regardless,
+    /// and the mismatch is handled as configured with `FixtureIo::on_mismatch`.
+    Mismatch(usize, Mismatch),
+}
+
+/// Expects an exact sequence of bytes, ignoring the contents of any masked
+/// ranges
+#[derive(Clone)]
+pub struct Exact {
+    data: Vec<u8>,
+    pos: usize,
+    mask: Vec<Range<usize>>,
+    options: WriteOptions,
+    // True if a `\r` preceding the current expected `\n` was skipped
+    skipped_cr: bool,
+}
+
+/// Collects a number of bytes, then compares them with the expected data all
+/// at once
+#[derive(Clone)]
+pub struct Deferred {
+    len: usize,
+    expected: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+/// Expects a number of bytes, without checking their contents
+#[derive(Debug, Clone)]
+pub struct Any {
+    remaining: usize,
+}
+
+/// Accepts a number of bytes, storing them under a name
+#[derive(Clone)]
+pub struct Capture {
+    remaining: usize,
+    name: String,
+    shared: Arc<Mutex<Shared>>,
+    started: bool,
+}
+
+/// Expects each of a set of payloads to be written once, in any order
+#[derive(Clone)]
+pub struct Unordered {
+    pending: Vec<Vec<u8>>,
+    // Pending payloads consistent with the data written so far
+    candidates: Vec<usize>,
+    // Position within the candidates
+    pos: usize,
+}
+
+/// Collects a number of bytes, then checks them with a function
+#[derive(Clone)]
+pub struct Predicate {
+    len: usize,
+    buf: Vec<u8>,
+    f: Arc<Mutex<FnMut(&[u8]) -> bool + Send>>,
+}
+
+/// Expects written data to match a regular expression
+#[cfg(feature = "regex")]
+#[derive(Clone)]
+pub struct Regex {
+    re: ::regex::bytes::Regex,
+    buf: Vec<u8>,
+    // Number of bytes buffered before giving up on a match
+    max_len: usize,
+    done: bool,
+}
+
+/// Expects an HTTP/1 request, comparing the method, path, headers and body.
+/// The order of headers with different names is ignored.
+#[cfg(feature = "http")]
+#[derive(Clone)]
+pub struct Http {
+    // Canonical form of the expected request
+    expected: Vec<u8>,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+/// Expects a JSON value, optionally following a delimiter. Values are compared
+/// structurally, so whitespace and the order of object keys are ignored.
+#[cfg(feature = "json")]
+#[derive(Clone)]
+pub struct Json {
+    expected: ::serde_json::Value,
+    // Data up to and including the delimiter is not checked
+    delimiter: Option<Vec<u8>>,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl Exact {
+    pub fn new(data: Vec<u8>) -> Exact {
+        Exact::masked(data, vec![])
+    }
+
+    pub fn masked(data: Vec<u8>, mask: Vec<Range<usize>>) -> Exact {
+        Exact {
+            data: data,
+            pos: 0,
+            mask: mask,
+            options: WriteOptions::default(),
+            skipped_cr: false,
+        }
+    }
+
+    pub fn options(mut self, options: WriteOptions) -> Exact {
+        if options.normalize_line_endings {
+            self.normalize_line_endings();
+        }
+
+        self.options = options;
+        self
+    }
+
+    /// Replaces `\r\n` with `\n` in the expected data, moving the mask to
+    /// match
+    fn normalize_line_endings(&mut self) {
+        let mut data = Vec::with_capacity(self.data.len());
+        // Number of bytes removed before each position of the original data
+        let mut removed = Vec::with_capacity(self.data.len() + 1);
+
+        for (i, &b) in self.data.iter().enumerate() {
+            removed.push(i - data.len());
+
+            if b != b'\r' || self.data.get(i + 1) != Some(&b'\n') {
+                data.push(b);
+            }
+        }
+
+        removed.push(self.data.len() - data.len());
+
+        for range in &mut self.mask {
+            let start = cmp::min(range.start, self.data.len());
+            let end = cmp::min(range.end, self.data.len());
+
+            *range = (start - removed[start])..(end - removed[end]);
+        }
+
+        self.data = data;
+    }
+
+    /// Compares written data, skipping a `\r` where `\n` is expected
+    fn matches_normalized(&mut self, data: &[u8]) -> MatchResult {
+        let base = self.pos;
+        let mut n = 0;
+        let mut actual = vec![];
+        let mut diff = None;
+
+        for &b in data {
+            let pos = base + actual.len();
+
+            if pos == self.data.len() {
+                break;
+            }
+
+            n += 1;
+
+            let expected = self.data[pos];
+
+            if b == b'\r' && expected == b'\n' && !self.skipped_cr {
+                self.skipped_cr = true;
+                continue;
+            }
+
+            self.skipped_cr = false;
+
+            if diff.is_none() && !self.is_match(pos, expected, b) {
+                diff = Some(actual.len());
+            }
+
+            actual.push(b);
+        }
+
+        self.pos += actual.len();
+
+        match diff {
+            Some(i) => {
+                let expected = &self.data[base..self.pos];
+                MatchResult::Mismatch(n, Mismatch::new(base, i, expected, &actual))
+            }
+            None => MatchResult::Matched(n),
+        }
+    }
+
+    /// Returns true if `actual` is accepted at `pos`, where `expected` is
+    /// the expected byte
+    fn is_match(&self, pos: usize, expected: u8, actual: u8) -> bool {
+        if expected == actual {
+            return true;
+        }
+
+        if self.options.ignore_case && expected.eq_ignore_ascii_case(&actual) {
+            return true;
+        }
+
+        self.mask.iter().any(|range| range.start <= pos && pos < range.end)
+    }
+}
+
+impl WriteMatcher for Exact {
+    fn matches(&mut self, data: &[u8]) -> MatchResult {
+        if self.options.normalize_line_endings {
+            return self.matches_normalized(data);
+        }
+
+        let base = self.pos;
+        let n = cmp::min(self.data.len() - base, data.len());
+
+        let expected = &self.data[base..base + n];
+        let actual = &data[..n];
+
+        let diff = expected.iter().zip(actual).enumerate()
+            .position(|(i, (&a, &b))| !self.is_match(base + i, a, b));
+
+        self.pos += n;
+
+        match diff {
+            Some(i) => MatchResult::Mismatch(n, Mismatch::new(base, i, expected, actual)),
+            None => MatchResult::Matched(n),
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    fn box_clone(&self) -> Option<Box<WriteMatcher>> {
+        Some(Box::new(self.clone()))
+    }
+
+    fn expected(&self) -> Option<Vec<u8>> {
+        Some(self.data[self.pos..].to_vec())
+    }
+
+    fn pending(&self) -> Option<&[u8]> {
+        Some(&self.data[self.pos..])
+    }
+}
+
+impl fmt::Debug for Exact {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Exact")
+            .field("remaining", &(self.data.len() - self.pos))
+            .finish()
+    }
+}
+
+impl Deferred {
+    /// # Panics
+    ///
+    /// Panics if the length of `expected` is not `len`.
+    pub fn new(len: usize, expected: Vec<u8>) -> Deferred {
+        assert_eq!(expected.len(), len, "expected data must be `len` bytes long");
+
+        Deferred {
+            len: len,
+            expected: expected,
+            buf: Vec::with_capacity(len),
+        }
+    }
+}
+
+impl WriteMatcher for Deferred {
+    fn matches(&mut self, data: &[u8]) -> MatchResult {
+        let n = cmp::min(self.len - self.buf.len(), data.len());
+        self.buf.extend_from_slice(&data[..n]);
+
+        if self.buf.len() == self.len && self.buf != self.expected {
+            let i = first_difference(&self.expected, &self.buf);
+            return MatchResult::Mismatch(n, Mismatch::new(0, i, &self.expected, &self.buf));
+        }
+
+        MatchResult::Matched(n)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.buf.len() == self.len
+    }
+
+    fn box_clone(&self) -> Option<Box<WriteMatcher>> {
+        Some(Box::new(self.clone()))
+    }
+
+    fn expected(&self) -> Option<Vec<u8>> {
+        if self.buf.is_empty() {
+            Some(self.expected.clone())
+        } else {
+            None
+        }
+    }
+
+    fn pending(&self) -> Option<&[u8]> {
+        Some(&self.expected[self.buf.len()..])
+    }
+}
+
+impl fmt::Debug for Deferred {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Deferred")
+            .field("remaining", &(self.len - self.buf.len()))
+            .finish()
+    }
+}
+
+impl Any {
+    pub fn new(len: usize) -> Any {
+        Any { remaining: len }
+    }
+}
+
+impl WriteMatcher for Any {
+    fn matches(&mut self, data: &[u8]) -> MatchResult {
+        let n = cmp::min(self.remaining, data.len());
+        self.remaining -= n;
+        MatchResult::Matched(n)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.remaining == 0
+    }
+
+    fn box_clone(&self) -> Option<Box<WriteMatcher>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+impl Capture {
+    pub fn new(len: usize, name: String, shared: Arc<Mutex<Shared>>) -> Capture {
+        Capture {
+            remaining: len,
+            name: name,
+            shared: shared,
+            started: false,
+        }
+    }
+}
+
+impl WriteMatcher for Capture {
+    fn matches(&mut self, data: &[u8]) -> MatchResult {
+        let n = cmp::min(self.remaining, data.len());
+        self.remaining -= n;
+
+        let mut shared = handle::lock(&self.shared);
+        let captured = shared.captures.entry(self.name.clone()).or_insert_with(Vec::new);
+
+        // Replace any earlier capture with the same name
+        if !self.started {
+            captured.clear();
+            self.started = true;
+        }
+
+        captured.extend_from_slice(&data[..n]);
+
+        MatchResult::Matched(n)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.remaining == 0
+    }
+
+    fn box_clone(&self) -> Option<Box<WriteMatcher>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+impl fmt::Debug for Capture {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Capture")
+            .field("name", &self.name)
+            .field("remaining", &self.remaining)
+            .finish()
+    }
+}
+
+impl Unordered {
+    pub fn new(payloads: Vec<Vec<u8>>) -> Unordered {
+        Unordered {
+            pending: payloads.into_iter().filter(|p| !p.is_empty()).collect(),
+            candidates: vec![],
+            pos: 0,
+        }
+    }
+
+    /// Moves past `n` bytes matching the candidates. A payload written in
+    /// full only completes once no longer candidate is consistent with the
+    /// data.
+    fn advance(&mut self, n: usize) {
+        self.pos += n;
+
+        let pos = self.pos;
+
+        if !self.candidates.iter().any(|&c| self.pending[c].len() > pos) {
+            self.complete();
+        }
+    }
+
+    /// Completes the candidate written in full, if any, and starts matching
+    /// the next payload
+    fn complete(&mut self) {
+        let pos = self.pos;
+        let done = self.candidates.iter().cloned()
+            .find(|&c| self.pending[c].len() == pos);
+
+        if let Some(done) = done {
+            self.pending.remove(done);
+        }
+
+        self.candidates.clear();
+        self.pos = 0;
+    }
+}
+
+impl WriteMatcher for Unordered {
+    fn matches(&mut self, data: &[u8]) -> MatchResult {
+        let mut n = 0;
+
+        while n < data.len() && !self.pending.is_empty() {
+            if self.candidates.is_empty() {
+                self.candidates = (0..self.pending.len()).collect();
+            }
+
+            let pos = self.pos;
+            let src = &data[n..];
+
+            // A payload was written in full, but longer ones are consistent
+            // with the data too. The next byte decides between them.
+            if self.candidates.iter().any(|&c| self.pending[c].len() == pos) {
+                let longer: Vec<usize> = self.candidates.iter().cloned()
+                    .filter(|&c| self.pending[c].len() > pos && self.pending[c][pos] == src[0])
+                    .collect();
+
+                if longer.is_empty() {
+                    self.complete();
+                    continue;
+                }
+
+                self.candidates = longer;
+            }
+
+            // Compare up to the end of the shortest candidate
+            let step = self.candidates.iter()
+                .map(|&c| self.pending[c].len() - pos)
+                .min().unwrap();
+            let step = cmp::min(step, src.len());
+            let actual = &src[..step];
+
+            let matching: Vec<usize> = self.candidates.iter().cloned()
+                .filter(|&c| &self.pending[c][pos..pos + step] == actual)
+                .collect();
+
+            if matching.is_empty() {
+                // Report the difference from the closest candidate, then
+                // continue as if it had been written
+                let best = self.candidates.iter().cloned()
+                    .max_by_key(|&c| first_difference(&self.pending[c][pos..], actual))
+                    .unwrap();
+
+                let mismatch = {
+                    let expected = &self.pending[best][pos..pos + step];
+                    let i = first_difference(expected, actual);
+                    Mismatch::new(pos, i, expected, actual)
+                };
+
+                self.candidates = vec![best];
+                self.advance(step);
+
+                return MatchResult::Mismatch(n + step, mismatch);
+            }
+
+            self.candidates = matching;
+            self.advance(step);
+            n += step;
+        }
+
+        MatchResult::Matched(n)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn box_clone(&self) -> Option<Box<WriteMatcher>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+impl fmt::Debug for Unordered {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Unordered")
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+impl Predicate {
+    pub fn new<F>(len: usize, f: F) -> Predicate
+        where F: FnMut(&[u8]) -> bool + Send + 'static,
+    {
+        Predicate {
+            len: len,
+            buf: Vec::with_capacity(len),
+            f: Arc::new(Mutex::new(f)),
+        }
+    }
+}
+
+impl WriteMatcher for Predicate {
+    fn matches(&mut self, data: &[u8]) -> MatchResult {
+        let n = cmp::min(self.len - self.buf.len(), data.len());
+        self.buf.extend_from_slice(&data[..n]);
+
+        if self.buf.len() == self.len && !(&mut *self.f.lock().unwrap())(&self.buf) {
+            // The expected bytes are not known
+            return MatchResult::Mismatch(n, Mismatch::new(0, 0, &[], &self.buf));
+        }
+
+        MatchResult::Matched(n)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.buf.len() == self.len
+    }
+
+    fn box_clone(&self) -> Option<Box<WriteMatcher>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+impl fmt::Debug for Predicate {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Predicate")
+            .field("remaining", &(self.len - self.buf.len()))
+            .finish()
+    }
+}
+
+#[cfg(feature = "regex")]
+impl Regex {
+    pub fn new(re: ::regex::bytes::Regex, max_len: usize) -> Regex {
+        Regex {
+            re: re,
+            buf: vec![],
+            max_len: max_len,
+            done: false,
+        }
+    }
+}
+
+#[cfg(feature = "regex")]
+impl WriteMatcher for Regex {
+    fn matches(&mut self, data: &[u8]) -> MatchResult {
+        let prev = self.buf.len();
+        let n = cmp::min(self.max_len - prev, data.len());
+        self.buf.extend_from_slice(&data[..n]);
+
+        // Only matches at the start of the data count, whether or not the
+        // pattern is anchored
+        let end = self.re.find(&self.buf)
+            .and_then(|m| if m.start() == 0 { Some(m.end()) } else { None });
+
+        match end {
+            Some(end) => {
+                // Only accept data up to the end of the match
+                let end = cmp::max(end, prev);
+                self.buf.truncate(end);
+                self.done = true;
+                MatchResult::Matched(end - prev)
+            }
+            None if self.buf.len() == self.max_len => {
+                // The expected bytes are not known
+                self.done = true;
+                MatchResult::Mismatch(n, Mismatch::new(0, 0, &[], &self.buf))
+            }
+            None => MatchResult::Matched(n),
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.done
+    }
+
+    fn box_clone(&self) -> Option<Box<WriteMatcher>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+#[cfg(feature = "regex")]
+impl fmt::Debug for Regex {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Regex")
+            .field("pattern", &self.re.as_str())
+            .field("buffered", &self.buf.len())
+            .field("max_len", &self.max_len)
+            .finish()
+    }
+}
+
+#[cfg(feature = "http")]
+impl Http {
+    /// # Panics
+    ///
+    /// Panics if `request` is not a complete HTTP/1 request.
+    pub fn new(request: &[u8]) -> Http {
+        match Http::parse(request) {
+            Ok(http) => http,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Returns an error if `request` is not a complete HTTP/1 request
+    pub fn parse(request: &[u8]) -> Result<Http, String> {
+        let expected = match parse_http(request) {
+            Ok(Some((_, canonical))) => canonical,
+            Ok(None) => return Err("incomplete HTTP request".into()),
+            Err(e) => return Err(format!("invalid HTTP request; {}", e)),
+        };
+
+        Ok(Http {
+            expected: expected,
+            buf: vec![],
+            done: false,
+        })
+    }
+}
+
+#[cfg(feature = "http")]
+impl WriteMatcher for Http {
+    fn matches(&mut self, data: &[u8]) -> MatchResult {
+        let prev = self.buf.len();
+        self.buf.extend_from_slice(data);
+
+        let (len, actual) = match parse_http(&self.buf) {
+            Ok(Some(parsed)) => parsed,
+            Ok(None) => return MatchResult::Matched(data.len()),
+            Err(_) => {
+                self.done = true;
+                let i = first_difference(&self.expected, &self.buf);
+                let mismatch = Mismatch::new(0, i, &self.expected, &self.buf);
+                return MatchResult::Mismatch(data.len(), mismatch);
+            }
+        };
+
+        // Only accept data up to the end of the request
+        self.buf.truncate(len);
+        self.done = true;
+
+        let n = len - prev;
+
+        if actual != self.expected {
+            let i = first_difference(&self.expected, &actual);
+            return MatchResult::Mismatch(n, Mismatch::new(0, i, &self.expected, &actual));
+        }
+
+        MatchResult::Matched(n)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.done
+    }
+
+    fn box_clone(&self) -> Option<Box<WriteMatcher>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+#[cfg(feature = "http")]
+impl fmt::Debug for Http {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Http")
+            .field("buffered", &self.buf.len())
+            .finish()
+    }
+}
+
+/// Parses an HTTP/1 request at the start of `buf`, returning its length and a
+/// canonical form with the headers sorted by name, or `None` if the request is
+/// not complete.
+#[cfg(feature = "http")]
+fn parse_http(buf: &[u8]) -> Result<Option<(usize, Vec<u8>)>, String> {
+    let mut headers = [::httparse::EMPTY_HEADER; 64];
+    let mut req = ::httparse::Request::new(&mut headers);
+
+    let head_len = match req.parse(buf) {
+        Ok(::httparse::Status::Complete(n)) => n,
+        Ok(::httparse::Status::Partial) => return Ok(None),
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let mut headers: Vec<(String, &[u8])> = req.headers.iter()
+        .map(|h| (h.name.to_ascii_lowercase(), h.value))
+        .collect();
+
+    // Stable, so repeated headers keep their relative order
+    headers.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let body_len = match headers.iter().find(|h| h.0 == "content-length") {
+        Some(&(_, value)) => {
+            try!(::std::str::from_utf8(value).ok()
+                .and_then(|v| v.trim().parse().ok())
+                .ok_or_else(|| "invalid content-length".to_string()))
+        }
+        None => 0,
+    };
+
+    let len = head_len + body_len;
+
+    if buf.len() < len {
+        return Ok(None);
+    }
+
+    let mut canonical = vec![];
+
+    canonical.extend_from_slice(req.method.unwrap_or("").as_bytes());
+    canonical.push(b' ');
+    canonical.extend_from_slice(req.path.unwrap_or("").as_bytes());
+    canonical.extend_from_slice(b"\r\n");
+
+    for (name, value) in headers {
+        canonical.extend_from_slice(name.as_bytes());
+        canonical.extend_from_slice(b": ");
+        canonical.extend_from_slice(value);
+        canonical.extend_from_slice(b"\r\n");
+    }
+
+    canonical.extend_from_slice(b"\r\n");
+    canonical.extend_from_slice(&buf[head_len..len]);
+
+    Ok(Some((len, canonical)))
+}
+
+#[cfg(feature = "json")]
+impl Json {
+    /// # Panics
+    ///
+    /// Panics if `expected` is not valid JSON.
+    pub fn new(expected: &str, delimiter: Option<Vec<u8>>) -> Json {
+        let expected = ::serde_json::from_str(expected)
+            .unwrap_or_else(|e| panic!("invalid JSON `{}`; {}", expected, e));
+
+        Json {
+            expected: expected,
+            delimiter: delimiter,
+            buf: vec![],
+            done: false,
+        }
+    }
+
+    /// Returns the position of the JSON value in the buffered data
+    fn body_start(&self) -> Option<usize> {
+        match self.delimiter {
+            Some(ref delimiter) => {
+                self.buf.windows(delimiter.len())
+                    .position(|w| w == &delimiter[..])
+                    .map(|i| i + delimiter.len())
+            }
+            None => Some(0),
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+impl WriteMatcher for Json {
+    fn matches(&mut self, data: &[u8]) -> MatchResult {
+        let prev = self.buf.len();
+        self.buf.extend_from_slice(data);
+
+        let start = match self.body_start() {
+            Some(start) => start,
+            None => return MatchResult::Matched(data.len()),
+        };
+
+        let parsed = {
+            let mut values = ::serde_json::Deserializer::from_slice(&self.buf[start..])
+                .into_iter::<::serde_json::Value>();
+
+            match values.next() {
+                Some(Ok(value)) => Ok((start + values.byte_offset(), value)),
+                Some(Err(ref e)) if e.is_eof() => return MatchResult::Matched(data.len()),
+                None => return MatchResult::Matched(data.len()),
+                Some(Err(e)) => Err(e),
+            }
+        };
+
+        self.done = true;
+
+        let expected = ::serde_json::to_vec(&self.expected).unwrap();
+
+        let (end, actual) = match parsed {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                let actual = &self.buf[start..];
+                let i = first_difference(&expected, actual);
+                let mismatch = Mismatch::new(start, i, &expected, actual);
+                return MatchResult::Mismatch(data.len(), mismatch);
+            }
+        };
+
+        // Only accept data up to the end of the value
+        self.buf.truncate(end);
+
+        let n = end - prev;
+
+        if actual != self.expected {
+            let actual = ::serde_json::to_vec(&actual).unwrap();
+            let i = first_difference(&expected, &actual);
+            return MatchResult::Mismatch(n, Mismatch::new(start, i, &expected, &actual));
+        }
+
+        MatchResult::Matched(n)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.done
+    }
+
+    fn box_clone(&self) -> Option<Box<WriteMatcher>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+#[cfg(feature = "json")]
+impl fmt::Debug for Json {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Json")
+            .field("expected", &self.expected)
+            .field("buffered", &self.buf.len())
+            .finish()
+    }
+}
+
+/// Returns the index of the first difference between `a` and `b`, or the
+/// length of the shorter one if it is a prefix of the other
+fn first_difference(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).position(|(a, b)| a != b)
+        .unwrap_or(cmp::min(a.len(), b.len()))
+}
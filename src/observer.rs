@@ -0,0 +1,34 @@
+use Mismatch;
+
+use std::io;
+
+/// Receives the activity of a `FixtureIo`, registered with
+/// `FixtureIo::with_observer`.
+///
+/// All methods do nothing by default.
+pub trait FixtureObserver: Send {
+    /// Called when the script reaches the action at `index`
+    fn on_action_start(&mut self, index: usize, description: &str) {
+        let _ = (index, description);
+    }
+
+    /// Called when the action at `index` completes
+    fn on_action_complete(&mut self, index: usize) {
+        let _ = index;
+    }
+
+    /// Called after each `read` call, with the data returned and the result
+    fn on_read(&mut self, data: &[u8], result: &io::Result<usize>) {
+        let _ = (data, result);
+    }
+
+    /// Called after each `write` call, with the data passed in and the result
+    fn on_write(&mut self, data: &[u8], result: &io::Result<usize>) {
+        let _ = (data, result);
+    }
+
+    /// Called when written data does not match the script
+    fn on_mismatch(&mut self, mismatch: &Mismatch) {
+        let _ = mismatch;
+    }
+}
@@ -0,0 +1,704 @@
+use Direction;
+
+use std::io;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+
+/// Selects the TCP connection replayed by `FixtureIo::load_pcap`.
+///
+/// The side that opened the connection is the client under test: the data
+/// it sent is expected to be written to the fixture, and the data it received
+/// is read from the fixture. When the capture starts after the handshake, the
+/// side using the higher port is taken to be the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamSelector {
+    /// The first connection in the capture
+    First,
+    /// The connection at the given index, counting from zero in the order
+    /// that the connections first appear in the capture
+    Index(usize),
+    /// The first connection to the given server port
+    Port(u16),
+    /// The first connection to the given server address
+    Server(SocketAddr),
+}
+
+/// Data transferred by the selected connection, relative to its first packet
+pub type Block = (Direction, Duration, Vec<u8>);
+
+/// Client and server addresses of a TCP connection
+type Conn = (SocketAddr, SocketAddr);
+
+/// A captured TCP packet. Its segment is an error if the packet was
+/// truncated or malformed, which only matters if it belongs to the selected
+/// connection.
+struct Packet<'a> {
+    src: SocketAddr,
+    dst: SocketAddr,
+    syn: bool,
+    ack: bool,
+    segment: io::Result<Segment<'a>>,
+}
+
+/// The part of a TCP segment used for reassembly
+struct Segment<'a> {
+    seq: u32,
+    syn: bool,
+    payload: &'a [u8],
+}
+
+/// The payload of an IP packet carrying TCP, with the reason it can't be used
+/// if it is incomplete
+struct Ip<'a> {
+    src: IpAddr,
+    dst: IpAddr,
+    payload: &'a [u8],
+    error: Option<io::Error>,
+}
+
+/// One direction of a TCP connection
+#[derive(Default)]
+struct Flow {
+    // Sequence number of the first byte of the stream, once known
+    isn: Option<u32>,
+    // Offset of the next byte to deliver
+    delivered: u32,
+    // Segments received ahead of `delivered`, by offset
+    pending: BTreeMap<u32, Vec<u8>>,
+}
+
+#[derive(Clone, Copy)]
+enum Link {
+    Ethernet,
+    Null,
+    Raw,
+    LinuxSll,
+    LinuxSll2,
+}
+
+struct Header {
+    big_endian: bool,
+    nanos: bool,
+    link: Link,
+}
+
+// The `network` field of the pcap header
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+
+const IPPROTO_TCP: u8 = 6;
+
+/// Reassembles the connection selected by `selector` from the capture at
+/// `path`
+pub fn load(path: &Path, selector: StreamSelector) -> io::Result<Vec<Block>> {
+    let mut data = vec![];
+    try!(try!(File::open(path)).read_to_end(&mut data));
+
+    reassemble(&data, selector)
+}
+
+fn reassemble(data: &[u8], selector: StreamSelector) -> io::Result<Vec<Block>> {
+    let header = try!(parse_header(data));
+
+    // Connections seen so far, as (client, server), by the addresses of
+    // either side
+    let mut conns: HashMap<Conn, Conn> = HashMap::new();
+    let mut selected: Option<Conn> = None;
+    // Client to server and server to client data of the selected connection
+    let mut flows = [Flow::default(), Flow::default()];
+
+    let mut origin = None;
+    let mut last = Duration::from_millis(0);
+    let mut blocks: Vec<Block> = vec![];
+
+    let mut pos = 24;
+
+    while pos < data.len() {
+        if data.len() < pos + 16 {
+            return Err(invalid("truncated packet record"));
+        }
+
+        let ts_sec = read_u32(&data[pos..], header.big_endian);
+        let ts_frac = read_u32(&data[pos + 4..], header.big_endian);
+        let len = read_u32(&data[pos + 8..], header.big_endian) as usize;
+        let orig_len = read_u32(&data[pos + 12..], header.big_endian) as usize;
+
+        pos += 16;
+
+        if data.len() < pos + len {
+            return Err(invalid("truncated packet record"));
+        }
+
+        let packet = &data[pos..pos + len];
+        pos += len;
+
+        // Packets whose connection can't be identified are skipped
+        let packet = match parse_packet(packet, header.link, len < orig_len) {
+            Some(packet) => packet,
+            None => continue,
+        };
+
+        let key = match conns.get(&(packet.src, packet.dst)) {
+            Some(&conn) => conn,
+            None => {
+                let conn = if packet.syn && packet.ack {
+                    (packet.dst, packet.src)
+                } else if packet.syn || packet.src.port() > packet.dst.port() {
+                    (packet.src, packet.dst)
+                } else {
+                    (packet.dst, packet.src)
+                };
+
+                // Each connection is stored under both orientations
+                let index = conns.len() / 2;
+
+                let matches = match selector {
+                    StreamSelector::First => index == 0,
+                    StreamSelector::Index(i) => index == i,
+                    StreamSelector::Port(port) => conn.1.port() == port,
+                    StreamSelector::Server(addr) => conn.1 == addr,
+                };
+
+                if matches && selected.is_none() {
+                    selected = Some(conn);
+                }
+
+                conns.insert((packet.src, packet.dst), conn);
+                conns.insert((packet.dst, packet.src), conn);
+                conn
+            }
+        };
+
+        if selected != Some(key) {
+            continue;
+        }
+
+        let segment = try!(packet.segment);
+
+        let nanos = if header.nanos { ts_frac } else { ts_frac.saturating_mul(1_000) };
+        let now = Duration::new(ts_sec as u64, nanos);
+
+        let origin = *origin.get_or_insert(now);
+
+        // Keep the blocks in order if the capture's clock went backwards
+        let elapsed = if now > origin { now - origin } else { Duration::from_millis(0) };
+        last = if elapsed > last { elapsed } else { last };
+
+        let (direction, flow) = if packet.src == key.0 {
+            (Direction::Write, &mut flows[0])
+        } else {
+            (Direction::Read, &mut flows[1])
+        };
+
+        let data = flow.push(&segment);
+
+        if data.is_empty() {
+            continue;
+        }
+
+        match blocks.last_mut() {
+            // Writes are matched as a single stream, so merge consecutive
+            // segments. Reads keep the timing between segments.
+            Some(&mut (Direction::Write, _, ref mut prev)) if direction == Direction::Write => {
+                prev.extend_from_slice(&data);
+            }
+            _ => blocks.push((direction, last, data)),
+        }
+    }
+
+    if selected.is_none() {
+        let msg = format!("no TCP connection in the capture matches {:?}", selector);
+        return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+    }
+
+    Ok(blocks)
+}
+
+impl Flow {
+    /// Adds a segment, returning the data that is now contiguous with the
+    /// data delivered so far
+    fn push(&mut self, segment: &Segment) -> Vec<u8> {
+        let isn = match self.isn {
+            Some(isn) => isn,
+            None => {
+                // The SYN takes up one sequence number. Without a SYN, the
+                // capture started partway through the stream.
+                let isn = if segment.syn { segment.seq.wrapping_add(1) } else { segment.seq };
+                self.isn = Some(isn);
+                isn
+            }
+        };
+
+        let mut offset = segment.seq.wrapping_add(segment.syn as u32).wrapping_sub(isn);
+        let mut payload = segment.payload;
+
+        if offset >= 1 << 31 {
+            // Starts before the first byte of the stream
+            let before = offset.wrapping_neg() as usize;
+
+            if before >= payload.len() {
+                return vec![];
+            }
+
+            payload = &payload[before..];
+            offset = 0;
+        }
+
+        if payload.is_empty() {
+            return vec![];
+        }
+
+        let replace = match self.pending.get(&offset) {
+            Some(pending) => pending.len() < payload.len(),
+            None => true,
+        };
+
+        if replace {
+            self.pending.insert(offset, payload.to_vec());
+        }
+
+        let mut ret = vec![];
+
+        loop {
+            let offset = match self.pending.keys().next() {
+                Some(&offset) if offset <= self.delivered => offset,
+                _ => break,
+            };
+
+            let data = self.pending.remove(&offset).unwrap();
+            let end = offset + data.len() as u32;
+
+            // Skip data that was already delivered, such as retransmissions
+            if end > self.delivered {
+                ret.extend_from_slice(&data[(self.delivered - offset) as usize..]);
+                self.delivered = end;
+            }
+        }
+
+        ret
+    }
+}
+
+fn parse_header(data: &[u8]) -> io::Result<Header> {
+    if data.len() < 24 {
+        return Err(invalid("truncated pcap header"));
+    }
+
+    let (big_endian, nanos) = match read_u32(data, false) {
+        0xa1b2c3d4 => (false, false),
+        0xa1b23c4d => (false, true),
+        0xd4c3b2a1 => (true, false),
+        0x4d3cb2a1 => (true, true),
+        0x0a0d0d0a => {
+            return Err(invalid("pcapng captures are not supported; convert the capture \
+                                with `editcap -F pcap`"));
+        }
+        _ => return Err(invalid("not a pcap capture")),
+    };
+
+    let link = match read_u32(&data[20..], big_endian) {
+        LINKTYPE_NULL => Link::Null,
+        LINKTYPE_ETHERNET => Link::Ethernet,
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => Link::Raw,
+        LINKTYPE_LINUX_SLL => Link::LinuxSll,
+        LINKTYPE_LINUX_SLL2 => Link::LinuxSll2,
+        link => return Err(invalid(&format!("unsupported link type {}", link))),
+    };
+
+    Ok(Header {
+        big_endian: big_endian,
+        nanos: nanos,
+        link: link,
+    })
+}
+
+/// Parses the TCP packet carried by `packet`, returning `None` for packets
+/// that do not carry TCP, or whose addresses and ports can't be parsed.
+/// `truncated` is set if the capture did not include the whole packet.
+fn parse_packet(packet: &[u8], link: Link, truncated: bool) -> Option<Packet> {
+    let ip = match link {
+        Link::Ethernet => {
+            let mut pos = 12;
+
+            loop {
+                if packet.len() < pos + 2 {
+                    return None;
+                }
+
+                match read_u16(&packet[pos..]) {
+                    ETHERTYPE_VLAN | ETHERTYPE_QINQ => pos += 4,
+                    ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => break,
+                    _ => return None,
+                }
+            }
+
+            &packet[pos + 2..]
+        }
+        Link::Null => {
+            if packet.len() < 4 {
+                return None;
+            }
+
+            &packet[4..]
+        }
+        Link::Raw => packet,
+        Link::LinuxSll => {
+            if packet.len() < 16 {
+                return None;
+            }
+
+            &packet[16..]
+        }
+        Link::LinuxSll2 => {
+            if packet.len() < 20 {
+                return None;
+            }
+
+            &packet[20..]
+        }
+    };
+
+    let ip = match ip.first().map(|b| b >> 4) {
+        Some(4) => parse_ipv4(ip, truncated),
+        Some(6) => parse_ipv6(ip, truncated),
+        _ => None,
+    };
+
+    let Ip { src, dst, payload: tcp, error } = match ip {
+        Some(ip) => ip,
+        None => return None,
+    };
+
+    // The ports identify the connection
+    if tcp.len() < 4 {
+        return None;
+    }
+
+    let src = SocketAddr::new(src, read_u16(tcp));
+    let dst = SocketAddr::new(dst, read_u16(&tcp[2..]));
+
+    let flags = tcp.get(13).cloned().unwrap_or(0);
+    let syn = flags & 0x02 != 0;
+
+    let segment = if let Some(error) = error {
+        Err(error)
+    } else if tcp.len() < 20 {
+        Err(invalid("truncated TCP header"))
+    } else {
+        let offset = (tcp[12] >> 4) as usize * 4;
+
+        if offset < 20 || tcp.len() < offset {
+            Err(invalid("invalid TCP header"))
+        } else {
+            Ok(Segment {
+                seq: read_u32(&tcp[4..], true),
+                syn: syn,
+                payload: &tcp[offset..],
+            })
+        }
+    };
+
+    Some(Packet {
+        src: src,
+        dst: dst,
+        syn: syn,
+        ack: flags & 0x10 != 0,
+        segment: segment,
+    })
+}
+
+fn parse_ipv4(ip: &[u8], truncated: bool) -> Option<Ip> {
+    if ip.len() < 20 || ip[9] != IPPROTO_TCP {
+        return None;
+    }
+
+    let header_len = (ip[0] & 0x0f) as usize * 4;
+    let total_len = read_u16(&ip[2..]) as usize;
+    let fragment = read_u16(&ip[6..]);
+
+    // Only the first fragment carries the TCP header
+    if header_len < 20 || ip.len() < header_len || fragment & 0x1fff != 0 {
+        return None;
+    }
+
+    let (payload, error) = if fragment & 0x2000 != 0 {
+        (&ip[header_len..], Some(invalid("fragmented IPv4 packets are not supported")))
+    } else if total_len < header_len {
+        (&ip[header_len..], Some(invalid("invalid IPv4 header")))
+    } else if ip.len() < total_len {
+        (&ip[header_len..], Some(truncated_packet(truncated)))
+    } else {
+        (&ip[header_len..total_len], None)
+    };
+
+    Some(Ip {
+        src: IpAddr::V4(Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15])),
+        dst: IpAddr::V4(Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19])),
+        payload: payload,
+        error: error,
+    })
+}
+
+fn parse_ipv6(ip: &[u8], truncated: bool) -> Option<Ip> {
+    if ip.len() < 40 {
+        return None;
+    }
+
+    let payload_len = read_u16(&ip[4..]) as usize;
+
+    let (mut payload, mut error) = if ip.len() < 40 + payload_len {
+        (&ip[40..], Some(truncated_packet(truncated)))
+    } else {
+        (&ip[40..40 + payload_len], None)
+    };
+
+    let mut next = ip[6];
+
+    // Skip the hop-by-hop, routing, fragment and destination options headers
+    while next == 0 || next == 43 || next == 44 || next == 60 {
+        let len = match next {
+            44 => 8,
+            _ if payload.len() >= 2 => (payload[1] as usize + 1) * 8,
+            _ => return None,
+        };
+
+        if payload.len() < len {
+            return None;
+        }
+
+        if next == 44 {
+            // Only the first fragment carries the TCP header
+            if read_u16(&payload[2..]) & 0xfff8 != 0 {
+                return None;
+            }
+
+            error = Some(invalid("fragmented IPv6 packets are not supported"));
+        }
+
+        next = payload[0];
+        payload = &payload[len..];
+    }
+
+    if next != IPPROTO_TCP {
+        return None;
+    }
+
+    let mut src = [0; 16];
+    let mut dst = [0; 16];
+    src.copy_from_slice(&ip[8..24]);
+    dst.copy_from_slice(&ip[24..40]);
+
+    Some(Ip {
+        src: IpAddr::V6(Ipv6Addr::from(src)),
+        dst: IpAddr::V6(Ipv6Addr::from(dst)),
+        payload: payload,
+        error: error,
+    })
+}
+
+fn truncated_packet(truncated: bool) -> io::Error {
+    if truncated {
+        invalid("packet truncated by the capture's snapshot length")
+    } else {
+        invalid("truncated IP packet")
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_u16(data: &[u8]) -> u16 {
+    (data[0] as u16) << 8 | data[1] as u16
+}
+
+fn read_u32(data: &[u8], big_endian: bool) -> u32 {
+    let b = [data[0] as u32, data[1] as u32, data[2] as u32, data[3] as u32];
+
+    if big_endian {
+        b[0] << 24 | b[1] << 16 | b[2] << 8 | b[3]
+    } else {
+        b[3] << 24 | b[2] << 16 | b[1] << 8 | b[0]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SYN: u8 = 0x02;
+    const ACK: u8 = 0x10;
+    const PSH: u8 = 0x08;
+
+    /// Builds an IPv4 packet carrying a TCP segment
+    fn tcp(src: ([u8; 4], u16), dst: ([u8; 4], u16), seq: u32, flags: u8, payload: &[u8])
+        -> Vec<u8>
+    {
+        let total_len = 40 + payload.len();
+
+        let mut ret = vec![0x45, 0, (total_len >> 8) as u8, total_len as u8,
+                           0, 0, 0x40, 0, 64, IPPROTO_TCP, 0, 0];
+        ret.extend_from_slice(&src.0);
+        ret.extend_from_slice(&dst.0);
+
+        ret.extend_from_slice(&[(src.1 >> 8) as u8, src.1 as u8, (dst.1 >> 8) as u8, dst.1 as u8]);
+        ret.extend_from_slice(&[(seq >> 24) as u8, (seq >> 16) as u8, (seq >> 8) as u8, seq as u8]);
+        ret.extend_from_slice(&[0, 0, 0, 0, 0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        ret.extend_from_slice(payload);
+        ret
+    }
+
+    /// Builds a little endian capture of raw IP packets. Each packet is
+    /// given with its timestamp in milliseconds and the number of bytes of
+    /// it that were captured.
+    fn capture(packets: &[(u32, Vec<u8>, usize)]) -> Vec<u8> {
+        let mut ret = vec![];
+        push_u32(&mut ret, 0xa1b2c3d4);
+        ret.extend_from_slice(&[2, 0, 4, 0]);
+        push_u32(&mut ret, 0);
+        push_u32(&mut ret, 0);
+        push_u32(&mut ret, 65535);
+        push_u32(&mut ret, LINKTYPE_RAW);
+
+        for &(ms, ref packet, captured) in packets {
+            push_u32(&mut ret, ms / 1000);
+            push_u32(&mut ret, ms % 1000 * 1000);
+            push_u32(&mut ret, captured as u32);
+            push_u32(&mut ret, packet.len() as u32);
+            ret.extend_from_slice(&packet[..captured]);
+        }
+
+        ret
+    }
+
+    fn push_u32(dst: &mut Vec<u8>, n: u32) {
+        dst.extend_from_slice(&[n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]);
+    }
+
+    fn whole(ms: u32, packet: Vec<u8>) -> (u32, Vec<u8>, usize) {
+        let len = packet.len();
+        (ms, packet, len)
+    }
+
+    const CLIENT: ([u8; 4], u16) = ([10, 0, 0, 1], 40000);
+    const SERVER: ([u8; 4], u16) = ([10, 0, 0, 2], 80);
+    const OTHER: ([u8; 4], u16) = ([10, 0, 0, 3], 50000);
+    const OTHER_SERVER: ([u8; 4], u16) = ([10, 0, 0, 2], 8080);
+
+    fn exchange() -> Vec<(u32, Vec<u8>, usize)> {
+        vec![
+            whole(0, tcp(CLIENT, SERVER, 100, SYN, b"")),
+            whole(1, tcp(SERVER, CLIENT, 500, SYN | ACK, b"")),
+            whole(2, tcp(CLIENT, SERVER, 101, ACK | PSH, b"GET / ")),
+            whole(3, tcp(CLIENT, SERVER, 107, ACK | PSH, b"HTTP/1.1\r\n\r\n")),
+            // Out of order, then retransmitted
+            whole(10, tcp(SERVER, CLIENT, 514, ACK | PSH, b"OK\r\n")),
+            whole(11, tcp(SERVER, CLIENT, 501, ACK | PSH, b"HTTP/1.1 200 ")),
+            whole(12, tcp(SERVER, CLIENT, 501, ACK | PSH, b"HTTP/1")),
+        ]
+    }
+
+    #[test]
+    fn reassembles_connection() {
+        let blocks = reassemble(&capture(&exchange()), StreamSelector::First).unwrap();
+
+        assert_eq!(blocks.len(), 2);
+
+        assert_eq!(blocks[0].0, Direction::Write);
+        assert_eq!(blocks[0].1, Duration::from_millis(2));
+        assert_eq!(blocks[0].2, b"GET / HTTP/1.1\r\n\r\n");
+
+        assert_eq!(blocks[1].0, Direction::Read);
+        assert_eq!(blocks[1].1, Duration::from_millis(11));
+        assert_eq!(blocks[1].2, b"HTTP/1.1 200 OK\r\n");
+    }
+
+    #[test]
+    fn selects_connection_by_port() {
+        let mut packets = vec![whole(0, tcp(OTHER, OTHER_SERVER, 1, SYN, b""))];
+        packets.extend(exchange());
+
+        let blocks = reassemble(&capture(&packets), StreamSelector::Port(80)).unwrap();
+        assert_eq!(blocks.len(), 2);
+
+        let err = reassemble(&capture(&packets), StreamSelector::Port(443)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn skips_truncated_packets_of_other_connections() {
+        let mut packets = vec![
+            // Cut short by the snapshot length, in the middle of the TCP
+            // header and after the ports
+            (0, tcp(OTHER, OTHER_SERVER, 1, ACK | PSH, b"ignored"), 30),
+            // Cut short before the ports
+            (0, tcp(OTHER, OTHER_SERVER, 1, ACK | PSH, b"ignored"), 22),
+        ];
+        packets.extend(exchange());
+
+        let blocks = reassemble(&capture(&packets), StreamSelector::Port(80)).unwrap();
+        assert_eq!(blocks.len(), 2);
+    }
+
+    #[test]
+    fn rejects_truncated_packet_of_selected_connection() {
+        let mut packets = exchange();
+        packets[2].2 -= 3;
+
+        let err = reassemble(&capture(&packets), StreamSelector::First).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_invalid_tcp_header_of_selected_connection() {
+        let mut packets = exchange();
+        // Data offset shorter than the minimum TCP header
+        packets[3].1[32] = 0x40;
+
+        let err = reassemble(&capture(&packets), StreamSelector::First).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Other connections' packets are only skipped
+        let mut other = tcp(OTHER, OTHER_SERVER, 1, ACK | PSH, b"ignored");
+        other[32] = 0x40;
+
+        let mut packets = vec![whole(0, other)];
+        packets.extend(exchange());
+
+        let blocks = reassemble(&capture(&packets), StreamSelector::Port(80)).unwrap();
+        assert_eq!(blocks.len(), 2);
+    }
+
+    #[test]
+    fn rejects_truncated_record() {
+        let mut data = capture(&exchange());
+        let len = data.len();
+        data.truncate(len - 2);
+
+        let err = reassemble(&data, StreamSelector::First).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        let mut data = capture(&[]);
+        data[..4].copy_from_slice(&[0x0a, 0x0d, 0x0d, 0x0a]);
+        assert!(reassemble(&data, StreamSelector::First).is_err());
+
+        assert!(reassemble(b"not a capture", StreamSelector::First).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use Direction;
+
+use io_dump::Dump;
+
+use std::{cmp, io};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Number of bytes on each data line of a saved block
+const LINE: usize = 16;
+
+/// Writes the data actually transferred by a fixture to an io_dump file
+pub struct Recorder {
+    dump: Dump<Replay, File>,
+    // Data for the next read through `dump`
+    pending: Arc<Mutex<Vec<u8>>>,
+}
+
+/// Transport behind the dump, returning the data being recorded as read and
+/// accepting all writes
+struct Replay {
+    pending: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Recorder> {
+        let pending = Arc::new(Mutex::new(vec![]));
+        let replay = Replay { pending: pending.clone() };
+
+        Ok(Recorder {
+            dump: try!(Dump::to_file(replay, path)),
+            pending: pending,
+        })
+    }
+
+    /// Records data returned by a `read` call
+    pub fn read(&mut self, data: &[u8]) -> io::Result<()> {
+        self.pending.lock().unwrap().extend_from_slice(data);
+
+        let mut buf = vec![0; data.len()];
+        let mut n = 0;
+
+        while n < data.len() {
+            match try!(self.dump.read(&mut buf[n..])) {
+                0 => break,
+                m => n += m,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records data accepted by a `write` call
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.dump.write_all(data)
+    }
+}
+
+impl Read for Replay {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        let mut pending = self.pending.lock().unwrap();
+
+        let n = cmp::min(dst.len(), pending.len());
+        dst[..n].copy_from_slice(&pending[..n]);
+        pending.drain(..n);
+
+        Ok(n)
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        Ok(src.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes `blocks` to an io_dump file at `path`, stamping each block with its
+/// elapsed time rather than the time it is written
+pub fn save(path: &Path, blocks: &[(Direction, Duration, Vec<u8>)]) -> io::Result<()> {
+    let mut dst = io::BufWriter::new(try!(File::create(path)));
+
+    for &(direction, elapsed, ref data) in blocks {
+        try!(write_block(&mut dst, direction, elapsed, data));
+    }
+
+    dst.flush()
+}
+
+/// Writes a block as `io_dump` does: a header with the direction and the
+/// elapsed time, the data in hex alongside its printable characters, and a
+/// blank line
+fn write_block<W: Write>(dst: &mut W, direction: Direction, elapsed: Duration, data: &[u8])
+    -> io::Result<()>
+{
+    let arrow = match direction {
+        Direction::Read => "<-",
+        Direction::Write => "->",
+    };
+
+    let millis = elapsed.subsec_nanos() / 1_000_000;
+    try!(write!(dst, "{} {}.{:03}s\n", arrow, elapsed.as_secs(), millis));
+
+    for line in data.chunks(LINE) {
+        try!(write!(dst, "    "));
+
+        for i in 0..LINE {
+            match line.get(i) {
+                Some(b) => try!(write!(dst, "{:02x} ", b)),
+                None => try!(write!(dst, "   ")),
+            }
+        }
+
+        try!(write!(dst, "   "));
+
+        for &b in line {
+            let c = if b >= 0x20 && b < 0x7f { b as char } else { '.' };
+            try!(write!(dst, "{}", c));
+        }
+
+        try!(write!(dst, "\n"));
+    }
+
+    write!(dst, "\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use io_dump::{self, DumpRead};
+
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn saved_blocks_load_back() {
+        let data: Vec<u8> = (0..40).collect();
+
+        let blocks = vec![
+            (Direction::Write, Duration::from_millis(0), b"GET / HTTP/1.1\r\n\r\n".to_vec()),
+            (Direction::Read, Duration::from_millis(1250), data),
+            (Direction::Write, Duration::from_millis(1250), b"x".to_vec()),
+            (Direction::Read, Duration::new(61, 5_000_000), b"bye".to_vec()),
+        ];
+
+        let path = env::temp_dir().join(format!("fixture-io-save-{}.dump", ::std::process::id()));
+        save(&path, &blocks).unwrap();
+
+        let loaded: Vec<_> = DumpRead::open(&path).unwrap().collect();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.len(), blocks.len());
+
+        for (block, &(direction, elapsed, ref data)) in loaded.iter().zip(&blocks) {
+            match (block.direction(), direction) {
+                (io_dump::Direction::Read, Direction::Read) |
+                (io_dump::Direction::Write, Direction::Write) => {}
+                _ => panic!("block loaded in the wrong direction"),
+            }
+
+            assert_eq!(block.elapsed(), elapsed);
+            assert_eq!(block.data(), &data[..]);
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use std::{cmp, error, fmt, io, mem};
+
+/// Responses to written requests, used once the script has completed
+#[derive(Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    // Written data that has not yet been matched to a rule
+    buf: Vec<u8>,
+    // Data queued for reading
+    responses: Vec<u8>,
+    // Used when written data does not match any rule
+    fallback: Option<Fallback>,
+}
+
+#[derive(Debug)]
+pub struct Rule {
+    request: Vec<u8>,
+    response: Vec<u8>,
+    // Bounds on the number of times the rule is used
+    min: usize,
+    max: Option<usize>,
+    calls: usize,
+}
+
+/// Reports rules that were used fewer or more times than expected
+#[derive(Debug, Clone)]
+pub struct VerifyError {
+    failures: Vec<Failure>,
+}
+
+#[derive(Debug, Clone)]
+struct Failure {
+    request: Vec<u8>,
+    min: usize,
+    max: Option<usize>,
+    calls: usize,
+}
+
+#[derive(Debug)]
+pub enum Fallback {
+    Respond(Vec<u8>),
+    Error(io::ErrorKind),
+}
+
+impl Rule {
+    pub fn new(request: Vec<u8>) -> Rule {
+        Rule {
+            request: request,
+            response: vec![],
+            min: 0,
+            max: None,
+            calls: 0,
+        }
+    }
+
+    pub fn set_calls(&mut self, min: usize, max: Option<usize>) {
+        self.min = min;
+        self.max = max;
+    }
+}
+
+impl Rules {
+    pub fn push(&mut self, mut rule: Rule, response: Vec<u8>) {
+        rule.response = response;
+        self.rules.push(rule);
+    }
+
+    pub fn set_fallback(&mut self, fallback: Fallback) {
+        self.fallback = Some(fallback);
+    }
+
+    /// Forgets the data written and queued so far, and how many times each
+    /// rule was used
+    pub fn reset(&mut self) {
+        self.buf.clear();
+        self.responses.clear();
+
+        for rule in &mut self.rules {
+            rule.calls = 0;
+        }
+    }
+
+    /// Returns true if any rules or a fallback are registered
+    pub fn is_active(&self) -> bool {
+        !self.rules.is_empty() || self.fallback.is_some()
+    }
+
+    /// Reads queued responses, returning `None` if there are none
+    pub fn read(&mut self, dst: &mut [u8]) -> Option<usize> {
+        if self.responses.is_empty() {
+            return None;
+        }
+
+        let n = cmp::min(dst.len(), self.responses.len());
+        dst[..n].copy_from_slice(&self.responses[..n]);
+        self.responses.drain(..n);
+
+        Some(n)
+    }
+
+    /// Matches written data against the rules, queuing the responses
+    pub fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(src);
+
+        while !self.buf.is_empty() {
+            let matched = self.rules.iter()
+                .position(|rule| self.buf.starts_with(&rule.request));
+
+            if let Some(i) = matched {
+                let rule = &mut self.rules[i];
+                rule.calls += 1;
+                self.responses.extend_from_slice(&rule.response);
+                self.buf.drain(..rule.request.len());
+                continue;
+            }
+
+            // Wait for more data if the request may still match a rule
+            if self.rules.iter().any(|rule| rule.request.starts_with(&self.buf)) {
+                break;
+            }
+
+            // All buffered data is treated as a single unmatched request
+            let data = mem::replace(&mut self.buf, vec![]);
+
+            match self.fallback {
+                Some(Fallback::Respond(ref response)) => {
+                    self.responses.extend_from_slice(response);
+                }
+                Some(Fallback::Error(kind)) => {
+                    return Err(kind.into());
+                }
+                None => {
+                    panic!("write did not match any rule; data={:?}", data);
+                }
+            }
+        }
+
+        Ok(src.len())
+    }
+
+    /// Checks that each rule was used the expected number of times
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        let failures: Vec<Failure> = self.rules.iter()
+            .filter(|rule| {
+                rule.calls < rule.min || rule.max.map(|max| rule.calls > max).unwrap_or(false)
+            })
+            .map(|rule| {
+                Failure {
+                    request: rule.request.clone(),
+                    min: rule.min,
+                    max: rule.max,
+                    calls: rule.calls,
+                }
+            })
+            .collect();
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(VerifyError { failures: failures })
+        }
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(fmt, "rule verification failed"));
+
+        for failure in &self.failures {
+            try!(write!(fmt, "; request={:?} expected ", failure.request));
+
+            match failure.max {
+                Some(max) if max == failure.min => try!(write!(fmt, "{}", max)),
+                Some(max) => try!(write!(fmt, "{} to {}", failure.min, max)),
+                None => try!(write!(fmt, "at least {}", failure.min)),
+            }
+
+            try!(write!(fmt, " calls, got {}", failure.calls));
+        }
+
+        Ok(())
+    }
+}
+
+impl error::Error for VerifyError {
+    fn description(&self) -> &str {
+        "rule verification failed"
+    }
+}
+
+impl fmt::Debug for Rules {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Rules")
+            .field("rules", &self.rules.len())
+            .field("buffered", &self.buf.len())
+            .field("responses", &self.responses.len())
+            .field("fallback", &self.fallback)
+            .finish()
+    }
+}
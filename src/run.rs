@@ -0,0 +1,85 @@
+use {FixtureIo, Clock, RealClock};
+use handle;
+
+use futures::{Future, IntoFuture};
+use futures::future::Either;
+
+use std::{error, fmt, io};
+use std::time::Duration;
+
+/// Error returned by `run`
+#[derive(Debug)]
+pub enum RunError<E> {
+    /// The client future failed
+    Client(E),
+    /// The client future did not complete in time
+    TimedOut {
+        /// The action the fixture was on, or `None` if the script had
+        /// completed
+        current: Option<String>,
+        /// The first few actions after the current one
+        remaining: Vec<String>,
+    },
+    /// The timer used for the deadline failed
+    Timer(io::Error),
+}
+
+/// Drives the future returned by `client` to completion on the current
+/// thread, failing if it takes longer than `timeout`.
+///
+/// `client` is passed `fixture` and returns the future under test. On
+/// timeout, the error describes where the script stalled.
+pub fn run<F, T>(fixture: FixtureIo, client: F, timeout: Duration)
+    -> Result<T::Item, RunError<T::Error>>
+    where F: FnOnce(FixtureIo) -> T,
+          T: IntoFuture,
+{
+    let handle = fixture.handle();
+    let deadline = RealClock::default().sleep(timeout);
+
+    match client(fixture).into_future().select2(deadline).wait() {
+        Ok(Either::A((item, _))) => Ok(item),
+        Err(Either::A((e, _))) => Err(RunError::Client(e)),
+        Ok(Either::B((_, client))) => {
+            let (current, remaining) = handle::progress(&handle);
+
+            // Only drop the client, and the fixture with it, once the
+            // progress has been read
+            drop(client);
+
+            Err(RunError::TimedOut {
+                current: current,
+                remaining: remaining,
+            })
+        }
+        Err(Either::B((e, _))) => Err(RunError::Timer(e)),
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for RunError<E> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RunError::Client(ref e) => write!(fmt, "client failed: {}", e),
+            RunError::TimedOut { ref current, ref remaining } => {
+                try!(write!(fmt, "client did not complete in time"));
+
+                if let Some(ref current) = *current {
+                    try!(write!(fmt, "; stalled at {}", current));
+                }
+
+                write!(fmt, "; remaining actions={:?}", remaining)
+            }
+            RunError::Timer(ref e) => write!(fmt, "timer failed: {}", e),
+        }
+    }
+}
+
+impl<E: error::Error> error::Error for RunError<E> {
+    fn description(&self) -> &str {
+        match *self {
+            RunError::Client(..) => "client failed",
+            RunError::TimedOut { .. } => "client did not complete in time",
+            RunError::Timer(..) => "timer failed",
+        }
+    }
+}
@@ -0,0 +1,116 @@
+#[cfg(feature = "serde")]
+use serde::ser::{Serialize, Serializer, SerializeStruct};
+
+use std::time::Duration;
+
+/// Start and end times of the actions reached by a fixture, returned by
+/// `FixtureIo::timeline`.
+///
+/// With the `serde` feature, the timeline implements `Serialize`, with times
+/// given in seconds since the fixture was created.
+#[derive(Debug, Clone, Default)]
+pub struct Timeline {
+    actions: Vec<TimelineEntry>,
+}
+
+/// An action reached by a fixture
+#[derive(Debug, Clone)]
+pub struct TimelineEntry {
+    index: usize,
+    name: Option<String>,
+    phase: Option<String>,
+    description: String,
+    started: Duration,
+    completed: Option<Duration>,
+}
+
+impl Timeline {
+    /// The actions reached so far, in order
+    pub fn actions(&self) -> &[TimelineEntry] {
+        &self.actions
+    }
+}
+
+impl TimelineEntry {
+    /// Index of the action within the script
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Name given to the action with `FixtureIo::named`
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|s| &s[..])
+    }
+
+    /// Phase containing the action, added with `FixtureIo::phase`
+    pub fn phase(&self) -> Option<&str> {
+        self.phase.as_ref().map(|s| &s[..])
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    /// Time since the fixture was created when the action started
+    pub fn started(&self) -> Duration {
+        self.started
+    }
+
+    /// Time since the fixture was created when the action completed, or
+    /// `None` if it has not
+    pub fn completed(&self) -> Option<Duration> {
+        self.completed
+    }
+
+    /// Time taken by the action, or `None` if it has not completed
+    pub fn duration(&self) -> Option<Duration> {
+        self.completed.map(|completed| completed - self.started)
+    }
+}
+
+/// Records the start of the action at `index`
+pub fn start(timeline: &mut Timeline,
+             index: usize,
+             name: Option<String>,
+             phase: Option<String>,
+             description: String,
+             now: Duration) {
+    timeline.actions.push(TimelineEntry {
+        index: index,
+        name: name,
+        phase: phase,
+        description: description,
+        started: now,
+        completed: None,
+    });
+}
+
+/// Records the completion of the action started most recently
+pub fn complete(timeline: &mut Timeline, now: Duration) {
+    if let Some(entry) = timeline.actions.last_mut() {
+        entry.completed = entry.completed.or(Some(now));
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for Timeline {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = try!(serializer.serialize_struct("Timeline", 1));
+        try!(state.serialize_field("actions", &self.actions));
+        state.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for TimelineEntry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = try!(serializer.serialize_struct("TimelineEntry", 6));
+        try!(state.serialize_field("index", &self.index));
+        try!(state.serialize_field("name", &self.name));
+        try!(state.serialize_field("phase", &self.phase));
+        try!(state.serialize_field("description", &self.description));
+        try!(state.serialize_field("started", &::as_secs_f64(self.started)));
+        try!(state.serialize_field("completed", &self.completed.map(::as_secs_f64)));
+        state.end()
+    }
+}
@@ -0,0 +1,394 @@
+use base64;
+
+use serde_yaml::Value;
+
+use std::io;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+/// An action of a scenario
+pub enum Step {
+    Read(Vec<u8>),
+    Write(Vec<u8>),
+    Wait(Duration),
+    Error(io::ErrorKind),
+    ReadError(io::ErrorKind),
+    WriteError(io::ErrorKind),
+    Eof,
+    Shutdown,
+    Flush,
+}
+
+/// Loads the steps of the scenario at `path`, in order, each with its label
+/// if one is given.
+///
+/// A scenario is a list of steps, either at the top level of the document or
+/// under a `steps` key:
+///
+/// ```text
+/// steps:
+///   - write: "GET / HTTP/1.1\r\n\r\n"
+///     label: request
+///   - wait: 50ms
+///   - read:
+///       hex: 48 54 54 50 2f 31 2e 31
+///   - read:
+///       base64: IDIwMCBPSw0KDQo=
+///   - error: connection_reset
+///   - eof
+/// ```
+pub fn load(path: &Path) -> io::Result<Vec<(Step, Option<String>)>> {
+    let mut text = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut text));
+
+    parse(&text)
+}
+
+fn parse(text: &str) -> io::Result<Vec<(Step, Option<String>)>> {
+    let doc: Value = match ::serde_yaml::from_str(text) {
+        Ok(doc) => doc,
+        Err(e) => return Err(invalid(&format!("invalid YAML; {}", e))),
+    };
+
+    let steps = match doc.as_sequence() {
+        Some(steps) => steps,
+        None => match doc["steps"].as_sequence() {
+            Some(steps) => steps,
+            None => return Err(invalid("expected a list of steps")),
+        },
+    };
+
+    let mut ret = vec![];
+
+    for (i, step) in steps.iter().enumerate() {
+        let step = try!(convert(step).map_err(|msg| {
+            invalid(&format!("step {}: {}", i, msg))
+        }));
+
+        ret.push(step);
+    }
+
+    Ok(ret)
+}
+
+fn convert(step: &Value) -> Result<(Step, Option<String>), String> {
+    // Steps without a payload may be given by name alone
+    if let Some(name) = step.as_str() {
+        return Ok((try!(bare(name)), None));
+    }
+
+    let fields = match step.as_mapping() {
+        Some(fields) => fields,
+        None => return Err("expected a mapping or the name of a step".into()),
+    };
+
+    let mut ret = None;
+    let mut label = None;
+
+    for (key, value) in fields.iter() {
+        let key = match key.as_str() {
+            Some(key) => key,
+            None => return Err("keys must be strings".into()),
+        };
+
+        if key == "label" {
+            match value.as_str() {
+                Some(value) => label = Some(value.to_string()),
+                None => return Err("`label` must be a string".into()),
+            }
+
+            continue;
+        }
+
+        let action = match key {
+            "read" => Step::Read(try!(payload(value))),
+            "write" => Step::Write(try!(payload(value))),
+            "wait" => Step::Wait(try!(duration(value))),
+            "error" => Step::Error(try!(error_kind(value))),
+            "read_error" => Step::ReadError(try!(error_kind(value))),
+            "write_error" => Step::WriteError(try!(error_kind(value))),
+            _ => return Err(format!("unknown key `{}`", key)),
+        };
+
+        if ret.is_some() {
+            return Err("expected a single action".into());
+        }
+
+        ret = Some(action);
+    }
+
+    match ret {
+        Some(action) => Ok((action, label)),
+        None => Err("missing action".into()),
+    }
+}
+
+fn bare(name: &str) -> Result<Step, String> {
+    match name {
+        "eof" => Ok(Step::Eof),
+        "shutdown" => Ok(Step::Shutdown),
+        "flush" => Ok(Step::Flush),
+        _ => Err(format!("unknown step `{}`", name)),
+    }
+}
+
+/// Returns the data given as a string, or as a mapping with a single `text`,
+/// `hex` or `base64` key
+fn payload(value: &Value) -> Result<Vec<u8>, String> {
+    if let Some(text) = value.as_str() {
+        return Ok(text.as_bytes().to_vec());
+    }
+
+    let fields = match value.as_mapping() {
+        Some(fields) if fields.len() == 1 => fields,
+        _ => return Err("expected a string or one of `text`, `hex` or `base64`".into()),
+    };
+
+    let (key, value) = fields.iter().next().unwrap();
+
+    let value = match value.as_str() {
+        Some(value) => value,
+        None => return Err("payload must be a string".into()),
+    };
+
+    match key.as_str() {
+        Some("text") => Ok(value.as_bytes().to_vec()),
+        Some("hex") => hex(value).ok_or_else(|| "invalid hex payload".to_string()),
+        Some("base64") => {
+            base64::decode(value).ok_or_else(|| "invalid base64 payload".to_string())
+        }
+        _ => Err("expected one of `text`, `hex` or `base64`".into()),
+    }
+}
+
+/// Decodes hex digits, ignoring whitespace
+fn hex(s: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = s.bytes().filter(|c| !(*c as char).is_whitespace()).collect();
+
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+
+    digits.chunks(2).map(|pair| {
+        let pair = match ::std::str::from_utf8(pair) {
+            Ok(pair) => pair,
+            Err(_) => return None,
+        };
+
+        u8::from_str_radix(pair, 16).ok()
+    }).collect()
+}
+
+/// Returns a duration given in milliseconds, or as a string with a `us`,
+/// `ms` or `s` suffix
+fn duration(value: &Value) -> Result<Duration, String> {
+    if let Some(ms) = value.as_u64() {
+        return Ok(Duration::from_millis(ms));
+    }
+
+    let s = match value.as_str() {
+        Some(s) => s.trim(),
+        None => return Err("expected a duration such as `100ms`".into()),
+    };
+
+    let (amount, nanos) = if s.ends_with("us") {
+        (&s[..s.len() - 2], 1_000.0)
+    } else if s.ends_with("ms") {
+        (&s[..s.len() - 2], 1_000_000.0)
+    } else if s.ends_with('s') {
+        (&s[..s.len() - 1], 1_000_000_000.0)
+    } else {
+        return Err(format!("duration `{}` has no unit", s));
+    };
+
+    let amount: f64 = match amount.trim().parse() {
+        Ok(amount) if amount >= 0.0 => amount,
+        _ => return Err(format!("invalid duration `{}`", s)),
+    };
+
+    let nanos = (amount * nanos) as u64;
+
+    Ok(Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32))
+}
+
+/// Returns the `io::ErrorKind` named either as in Rust, `ConnectionReset`, or
+/// in snake case, `connection_reset`
+fn error_kind(value: &Value) -> Result<io::ErrorKind, String> {
+    use std::io::ErrorKind::*;
+
+    let name = match value.as_str() {
+        Some(name) => name,
+        None => return Err("expected the name of an error kind".into()),
+    };
+
+    let normalized: String = name.chars()
+        .filter(|c| *c != '_')
+        .flat_map(|c| c.to_lowercase())
+        .collect();
+
+    let kind = match &normalized[..] {
+        "notfound" => NotFound,
+        "permissiondenied" => PermissionDenied,
+        "connectionrefused" => ConnectionRefused,
+        "connectionreset" => ConnectionReset,
+        "connectionaborted" => ConnectionAborted,
+        "notconnected" => NotConnected,
+        "addrinuse" => AddrInUse,
+        "addrnotavailable" => AddrNotAvailable,
+        "brokenpipe" => BrokenPipe,
+        "alreadyexists" => AlreadyExists,
+        "wouldblock" => WouldBlock,
+        "invalidinput" => InvalidInput,
+        "invaliddata" => InvalidData,
+        "timedout" => TimedOut,
+        "writezero" => WriteZero,
+        "interrupted" => Interrupted,
+        "unexpectedeof" => UnexpectedEof,
+        "other" => Other,
+        _ => return Err(format!("unknown error kind `{}`", name)),
+    };
+
+    Ok(kind)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    let msg = format!("invalid YAML scenario: {}", msg);
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        match parse(text) {
+            Ok(_) => panic!("scenario parsed; {}", text),
+            Err(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+                e.to_string()
+            }
+        }
+    }
+
+    #[test]
+    fn parses_scenario() {
+        let steps = parse("\
+steps:
+  - write: \"GET / HTTP/1.1\\r\\n\\r\\n\"
+    label: request
+  - wait: 50ms
+  - read:
+      hex: 48 54 54 50 2f 31 2e 31
+  - read:
+      base64: IDIwMCBPSw0KDQo=
+  - read:
+      text: done
+  - error: connection_reset
+  - read_error: WouldBlock
+  - eof
+").unwrap();
+
+        assert_eq!(steps.len(), 8);
+
+        match steps[0] {
+            (Step::Write(ref data), Some(ref label)) => {
+                assert_eq!(data, b"GET / HTTP/1.1\r\n\r\n");
+                assert_eq!(label, "request");
+            }
+            _ => panic!("expected a labelled write"),
+        }
+
+        match steps[1] {
+            (Step::Wait(dur), None) => assert_eq!(dur, Duration::from_millis(50)),
+            _ => panic!("expected a wait"),
+        }
+
+        match steps[2] {
+            (Step::Read(ref data), None) => assert_eq!(data, b"HTTP/1.1"),
+            _ => panic!("expected a read"),
+        }
+
+        match steps[3] {
+            (Step::Read(ref data), None) => assert_eq!(data, b" 200 OK\r\n\r\n"),
+            _ => panic!("expected a read"),
+        }
+
+        match steps[4] {
+            (Step::Read(ref data), None) => assert_eq!(data, b"done"),
+            _ => panic!("expected a read"),
+        }
+
+        match steps[5] {
+            (Step::Error(kind), None) => assert_eq!(kind, io::ErrorKind::ConnectionReset),
+            _ => panic!("expected an error"),
+        }
+
+        match steps[6] {
+            (Step::ReadError(kind), None) => assert_eq!(kind, io::ErrorKind::WouldBlock),
+            _ => panic!("expected a read error"),
+        }
+
+        match steps[7] {
+            (Step::Eof, None) => {}
+            _ => panic!("expected an eof"),
+        }
+    }
+
+    #[test]
+    fn parses_top_level_list() {
+        let steps = parse("- shutdown\n- flush\n").unwrap();
+
+        assert_eq!(steps.len(), 2);
+
+        match (&steps[0], &steps[1]) {
+            (&(Step::Shutdown, None), &(Step::Flush, None)) => {}
+            _ => panic!("expected a shutdown and a flush"),
+        }
+    }
+
+    #[test]
+    fn parses_durations() {
+        let cases = [
+            ("100", Duration::from_millis(100)),
+            ("1.5s", Duration::from_millis(1500)),
+            ("250us", Duration::new(0, 250_000)),
+            ("2 ms", Duration::from_millis(2)),
+        ];
+
+        for &(text, expected) in &cases {
+            match parse(&format!("- wait: {}", text)).unwrap()[0] {
+                (Step::Wait(dur), _) => assert_eq!(dur, expected, "{}", text),
+                _ => panic!("expected a wait"),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_scenarios() {
+        let cases = [
+            ("steps: [", "invalid YAML"),
+            ("steps: 1", "expected a list of steps"),
+            ("- jump", "unknown step"),
+            ("- 1", "expected a mapping"),
+            ("- sleep: 1", "unknown key"),
+            ("- read: a\n  write: b", "expected a single action"),
+            ("- label: x", "missing action"),
+            ("- read: a\n  label: [x]", "`label` must be a string"),
+            ("- read:\n    hex: abc", "invalid hex"),
+            ("- read:\n    hex: zz", "invalid hex"),
+            ("- read:\n    base64: \"!\"", "invalid base64"),
+            ("- read:\n    utf8: a", "expected one of"),
+            ("- read: [a]", "expected a string"),
+            ("- wait: 5m", "no unit"),
+            ("- wait: -1s", "invalid duration"),
+            ("- error: on_fire", "unknown error kind"),
+        ];
+
+        for &(text, msg) in &cases[..] {
+            let e = error(text);
+            assert!(e.contains(msg), "{}: {}", text, e);
+        }
+    }
+}